@@ -0,0 +1,232 @@
+//! Declaring the AMQP exchanges and queues oxxy publishes to and consumes from
+
+use clap::ValueEnum;
+use lapin::options::{ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions};
+use lapin::types::{AMQPValue, FieldTable, LongString};
+use lapin::{Channel, ExchangeKind};
+
+/// The type of exchange to declare
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ExchangeType {
+    /// route on the exact routing key
+    #[default]
+    Direct,
+    /// route on routing key patterns such as `logs.*.#`
+    Topic,
+    /// route to every bound queue
+    Fanout,
+    /// route on message headers, see `--bind-header`
+    Headers,
+}
+
+impl From<ExchangeType> for ExchangeKind {
+    fn from(kind: ExchangeType) -> Self {
+        match kind {
+            ExchangeType::Direct => ExchangeKind::Direct,
+            ExchangeType::Topic => ExchangeKind::Topic,
+            ExchangeType::Fanout => ExchangeKind::Fanout,
+            ExchangeType::Headers => ExchangeKind::Headers,
+        }
+    }
+}
+
+/// The type of queue to declare
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum QueueType {
+    #[default]
+    Classic,
+    /// replicated, always durable
+    Quorum,
+    /// append-only log, always durable
+    Stream,
+}
+
+impl QueueType {
+    /// The `x-queue-type` the broker knows the type as
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueType::Classic => "classic",
+            QueueType::Quorum => "quorum",
+            QueueType::Stream => "stream",
+        }
+    }
+}
+
+/// How the exchange is declared, flattened into every AMQP command
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ExchangeArgs {
+    #[arg(long, value_enum, default_value = "direct")]
+    pub exchange_kind: ExchangeType,
+    /// Declare exchanges and queues durable, so they survive a broker restart
+    #[arg(long)]
+    pub durable: bool,
+    /// Only check that exchanges and queues exist, without declaring or binding anything
+    #[arg(long)]
+    pub passive: bool,
+}
+
+/// How the consumed queue is declared and bound, on top of its exchange
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct TopologyArgs {
+    #[command(flatten)]
+    pub exchange: ExchangeArgs,
+    #[arg(long, value_enum, default_value = "classic")]
+    pub queue_type: QueueType,
+    /// Milliseconds a message may wait in the queue before it is dropped or dead-lettered
+    #[arg(long)]
+    pub message_ttl: Option<u64>,
+    /// Messages the queue holds before the oldest are dropped or dead-lettered
+    #[arg(long)]
+    pub max_length: Option<u64>,
+    /// Bytes of messages the queue holds before the oldest are dropped or dead-lettered
+    #[arg(long)]
+    pub max_length_bytes: Option<u64>,
+    /// Header a message needs to be routed to the queue by a headers exchange, as name=value
+    #[arg(long = "bind-header", value_parser = parse_header)]
+    pub bind_headers: Vec<(String, String)>,
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected name=value, got {s}"))
+}
+
+impl ExchangeArgs {
+    pub fn exchange_options(&self) -> ExchangeDeclareOptions {
+        ExchangeDeclareOptions {
+            passive: self.passive,
+            durable: self.durable,
+            ..Default::default()
+        }
+    }
+
+    /// Declare `exchange` as `--exchange-kind`
+    pub async fn declare_exchange(
+        &self,
+        channel: &Channel,
+        exchange: &str,
+    ) -> Result<(), lapin::Error> {
+        channel
+            .exchange_declare(
+                exchange,
+                self.exchange_kind.into(),
+                self.exchange_options(),
+                FieldTable::default(),
+            )
+            .await
+    }
+}
+
+impl TopologyArgs {
+    /// Refuse limits the queue type doesn't support, before anything is declared
+    pub fn validate(&self) -> Result<(), String> {
+        if self.queue_type == QueueType::Stream {
+            for (flag, set) in [
+                ("--message-ttl", self.message_ttl.is_some()),
+                ("--max-length", self.max_length.is_some()),
+            ] {
+                if set {
+                    return Err(format!("{flag} can't be used with --queue-type stream"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn queue_options(&self) -> QueueDeclareOptions {
+        QueueDeclareOptions {
+            passive: self.exchange.passive,
+            durable: self.exchange.durable || self.queue_type != QueueType::Classic,
+            ..Default::default()
+        }
+    }
+
+    /// Declare `queue` with the queue type and limits asked for, on top of `arguments`
+    pub async fn declare_queue(
+        &self,
+        channel: &Channel,
+        queue: &str,
+        mut arguments: FieldTable,
+    ) -> Result<(), lapin::Error> {
+        if self.queue_type != QueueType::Classic {
+            arguments.insert("x-queue-type".into(), long_string(self.queue_type.as_str()));
+        }
+        for (name, limit) in [
+            ("x-message-ttl", self.message_ttl),
+            ("x-max-length", self.max_length),
+            ("x-max-length-bytes", self.max_length_bytes),
+        ] {
+            if let Some(limit) = limit {
+                arguments.insert(name.into(), AMQPValue::LongLongInt(limit as i64));
+            }
+        }
+        channel
+            .queue_declare(queue, self.queue_options(), arguments)
+            .await?;
+        Ok(())
+    }
+
+    /// Bind `queue` to `exchange`, matching `--bind-header`s on a headers exchange
+    pub async fn bind(
+        &self,
+        channel: &Channel,
+        queue: &str,
+        exchange: &str,
+        routing_key: &str,
+    ) -> Result<(), lapin::Error> {
+        if self.exchange.passive {
+            return Ok(());
+        }
+        let mut arguments = FieldTable::default();
+        if self.exchange.exchange_kind == ExchangeType::Headers {
+            arguments.insert("x-match".into(), long_string("all"));
+            for (name, value) in &self.bind_headers {
+                arguments.insert(name.as_str().into(), long_string(value));
+            }
+        }
+        channel
+            .queue_bind(
+                queue,
+                exchange,
+                routing_key,
+                QueueBindOptions::default(),
+                arguments,
+            )
+            .await
+    }
+}
+
+pub fn long_string(s: &str) -> AMQPValue {
+    AMQPValue::LongString(LongString::from(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quorum_queues_are_durable() {
+        let mut topology = TopologyArgs::default();
+        assert!(!topology.queue_options().durable);
+        topology.queue_type = QueueType::Quorum;
+        assert!(topology.queue_options().durable);
+        assert!(parse_header("X-Scope-OrgID=tenant-a").is_ok());
+        assert!(parse_header("tenant-a").is_err());
+    }
+
+    #[test]
+    fn streams_refuse_ttls() {
+        let mut topology = TopologyArgs {
+            queue_type: QueueType::Stream,
+            max_length_bytes: Some(1 << 30),
+            ..Default::default()
+        };
+        assert_eq!(topology.queue_type.as_str(), "stream");
+        assert!(topology.validate().is_ok());
+        topology.message_ttl = Some(60_000);
+        assert!(topology.validate().is_err());
+        topology.queue_type = QueueType::Quorum;
+        assert!(topology.validate().is_ok());
+    }
+}
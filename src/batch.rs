@@ -0,0 +1,380 @@
+//! Merging decoded pushes into fewer, larger Loki requests
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot::error::TryRecvError;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::time::Instant;
+
+use crate::shapes::{Entry, Format, Labels, OutputFormat, Payload, PushRequest, Stream};
+
+/// When a batch is sent on, flattened into the consumers' args
+#[derive(clap::Args, Debug, Clone)]
+pub struct BatchArgs {
+    /// Entries a batch may hold, the default of 1 pushes every message on its own
+    #[arg(long, default_value = "1")]
+    pub batch_entries: usize,
+    /// Bytes of log lines a batch may hold
+    #[arg(long, default_value = "1048576")]
+    pub batch_bytes: usize,
+    /// Milliseconds a batch waits for more entries before it is sent anyway
+    #[arg(long, default_value = "1000")]
+    pub batch_wait: u64,
+    /// Batches being pushed at once, further batches wait for one of them to finish
+    #[arg(long, default_value = "4")]
+    pub batch_flushes: usize,
+}
+
+/// A batch ready to push, with the tokens of every message merged into it
+///
+/// `payload` is the error when the merged push couldn't be encoded, so the tokens can still be
+/// settled.
+#[derive(Debug)]
+pub struct Flush<T> {
+    pub tenant: Option<String>,
+    pub payload: Result<Payload, anyhow::Error>,
+    pub entries: usize,
+    pub tokens: Vec<T>,
+}
+
+/// Pushes waiting to be sent for one tenant in one format
+struct Batch<T> {
+    streams: BTreeMap<Labels, Vec<Entry>>,
+    entries: usize,
+    bytes: usize,
+    tokens: Vec<T>,
+    // a batch of one push is sent exactly as it was received
+    received: Option<Payload>,
+    deadline: Instant,
+}
+
+impl<T> Batch<T> {
+    fn new(deadline: Instant) -> Self {
+        Batch {
+            streams: BTreeMap::new(),
+            entries: 0,
+            bytes: 0,
+            tokens: vec![],
+            received: None,
+            deadline,
+        }
+    }
+
+    fn add(&mut self, push: PushRequest, received: Payload, token: T) {
+        self.received = match self.tokens.is_empty() {
+            true => Some(received),
+            false => None,
+        };
+        self.tokens.push(token);
+        for stream in push.streams {
+            self.entries += stream.values.len();
+            self.bytes += stream.values.iter().map(entry_bytes).sum::<usize>();
+            self.streams
+                .entry(stream.labels)
+                .or_default()
+                .extend(stream.values);
+        }
+    }
+
+    fn payload(self, output: OutputFormat, format: Format) -> Result<Payload, anyhow::Error> {
+        let push = PushRequest {
+            streams: self
+                .streams
+                .into_iter()
+                .map(|(labels, values)| Stream { labels, values })
+                .collect(),
+        };
+        match self.received {
+            Some(received) => output.convert(&push, received),
+            None => Ok(Payload {
+                body: push.encode(format)?,
+                format,
+                content_encoding: None,
+            }),
+        }
+    }
+}
+
+fn entry_bytes(entry: &Entry) -> usize {
+    entry.line.len()
+        + entry
+            .structured_metadata
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum::<usize>()
+}
+
+struct Item<T> {
+    tenant: Option<String>,
+    push: PushRequest,
+    received: Payload,
+    token: T,
+}
+
+/// Hands pushes to a background task that merges them by tenant, format and label set
+pub struct Batcher<T> {
+    tx: mpsc::Sender<Item<T>>,
+}
+
+// derived Clone would needlessly require the tokens to be Clone
+impl<T> Clone for Batcher<T> {
+    fn clone(&self) -> Self {
+        Batcher {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> Batcher<T> {
+    /// Start batching, every full or expired batch is handed to `flush` on its own task
+    ///
+    /// At most `--batch-flushes` flushes run at once, past that batching waits and so does
+    /// [`Batcher::add`]. Batches of one tenant and format are flushed one after the other, so a
+    /// stream's entries reach Loki in order.
+    pub fn spawn<F, Fut>(args: BatchArgs, output: OutputFormat, flush: F) -> Self
+    where
+        F: Fn(Flush<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel::<Item<T>>(1024);
+        let wait = Duration::from_millis(args.batch_wait);
+        tokio::spawn(async move {
+            let mut batches: HashMap<(Option<String>, Format), Batch<T>> = HashMap::new();
+            let mut flushes = Flushes::new(args.batch_flushes);
+            loop {
+                let deadline = batches.values().map(|batch| batch.deadline).min();
+                let item = match deadline {
+                    Some(deadline) => tokio::select! {
+                        item = rx.recv() => item,
+                        _ = tokio::time::sleep_until(deadline) => {
+                            let now = Instant::now();
+                            let expired: Vec<_> = batches
+                                .iter()
+                                .filter(|(_, batch)| batch.deadline <= now)
+                                .map(|(key, _)| key.clone())
+                                .collect();
+                            for key in expired {
+                                if let Some(batch) = batches.remove(&key) {
+                                    flushes.send(&flush, output, key, batch).await;
+                                }
+                            }
+                            continue;
+                        }
+                    },
+                    None => rx.recv().await,
+                };
+                let Some(item) = item else {
+                    // every sender is gone, send what is left and stop
+                    for (key, batch) in batches.drain() {
+                        flushes.send(&flush, output, key, batch).await;
+                    }
+                    break;
+                };
+                let key = (item.tenant, output.resolve(item.received.format));
+                let batch = batches
+                    .entry(key.clone())
+                    .or_insert_with(|| Batch::new(Instant::now() + wait));
+                batch.add(item.push, item.received, item.token);
+                if batch.entries >= args.batch_entries || batch.bytes >= args.batch_bytes {
+                    if let Some(batch) = batches.remove(&key) {
+                        flushes.send(&flush, output, key, batch).await;
+                    }
+                }
+            }
+        });
+        Batcher { tx }
+    }
+
+    /// Queue a decoded push, waiting while the batching task is behind
+    pub async fn add(
+        &self,
+        tenant: Option<String>,
+        push: PushRequest,
+        received: Payload,
+        token: T,
+    ) -> Result<(), anyhow::Error> {
+        self.tx
+            .send(Item {
+                tenant,
+                push,
+                received,
+                token,
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("batcher stopped"))
+    }
+}
+
+/// The flushes under way, bounded and chained per tenant and format
+struct Flushes {
+    permits: Arc<Semaphore>,
+    // finishes once the latest flush for the key is done
+    latest: HashMap<(Option<String>, Format), oneshot::Receiver<()>>,
+}
+
+impl Flushes {
+    fn new(limit: usize) -> Self {
+        Flushes {
+            permits: Arc::new(Semaphore::new(limit.max(1))),
+            latest: HashMap::new(),
+        }
+    }
+
+    /// Hand `batch` to `flush` once a flush is free, to run after the previous one for its key
+    async fn send<T, F, Fut>(
+        &mut self,
+        flush: &F,
+        output: OutputFormat,
+        key: (Option<String>, Format),
+        mut batch: Batch<T>,
+    ) where
+        F: Fn(Flush<T>) -> Fut + Sync,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let entries = batch.entries;
+        let tokens = std::mem::take(&mut batch.tokens);
+        let payload = batch.payload(output, key.1);
+        let permit = self.permits.clone().acquire_owned().await.unwrap();
+        let (done, latest) = oneshot::channel();
+        let previous = self.latest.insert(key.clone(), latest);
+        // forget keys whose flushes are all done, so idle tenants don't pile up
+        self.latest
+            .retain(|_, latest| matches!(latest.try_recv(), Err(TryRecvError::Empty)));
+        let flushed = flush(Flush {
+            tenant: key.0,
+            payload,
+            entries,
+            tokens,
+        });
+        tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            flushed.await;
+            let _ = done.send(());
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::Timestamp;
+
+    fn push(app: &str, line: &str) -> PushRequest {
+        PushRequest {
+            streams: vec![Stream {
+                labels: [("app", app)].into_iter().collect(),
+                values: vec![Entry::new(Timestamp::from_nanos(1), line)],
+            }],
+        }
+    }
+
+    fn received(push: &PushRequest) -> Payload {
+        Payload {
+            body: push.encode(Format::Json).unwrap(),
+            format: Format::Json,
+            content_encoding: None,
+        }
+    }
+
+    #[test]
+    fn merges_by_label_set() {
+        let mut batch = Batch::new(Instant::now());
+        for (app, line) in [("web", "a"), ("db", "b"), ("web", "c")] {
+            let push = push(app, line);
+            let received = received(&push);
+            batch.add(push, received, ());
+        }
+        assert_eq!((batch.entries, batch.bytes, batch.tokens.len()), (3, 3, 3));
+
+        let payload = batch
+            .payload(OutputFormat::Passthrough, Format::Json)
+            .unwrap();
+        let merged = PushRequest::decode(&payload.body, payload.format).unwrap();
+        assert_eq!(merged.streams.len(), 2);
+        assert_eq!(merged.entries(), 3);
+    }
+
+    #[test]
+    fn single_push_passes_through() {
+        let push = push("web", "a");
+        let mut received = received(&push);
+        received.body.push(b'\n');
+        let mut batch = Batch::new(Instant::now());
+        batch.add(push, received.clone(), ());
+        let payload = batch
+            .payload(OutputFormat::Passthrough, Format::Json)
+            .unwrap();
+        assert_eq!(payload, received);
+    }
+
+    #[tokio::test]
+    async fn bounds_and_orders_flushes() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Mutex;
+
+        let args = BatchArgs {
+            batch_entries: 1,
+            batch_bytes: 1024,
+            batch_wait: 1000,
+            batch_flushes: 2,
+        };
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let flushed = Arc::new(Mutex::new(vec![]));
+        let batcher = {
+            let (running, most, flushed) = (running.clone(), most.clone(), flushed.clone());
+            Batcher::spawn(
+                args,
+                OutputFormat::Passthrough,
+                move |batch: Flush<usize>| {
+                    let (running, most, flushed) = (running.clone(), most.clone(), flushed.clone());
+                    async move {
+                        most.fetch_max(
+                            running.fetch_add(1, Ordering::SeqCst) + 1,
+                            Ordering::SeqCst,
+                        );
+                        // later batches finish sooner, they must still not overtake
+                        tokio::time::sleep(Duration::from_millis(20 - batch.tokens[0] as u64))
+                            .await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        flushed
+                            .lock()
+                            .unwrap()
+                            .push((batch.tenant, batch.tokens[0]));
+                    }
+                },
+            )
+        };
+        for token in 0..12 {
+            let tenant = ["a", "b", "c"][token % 3].to_string();
+            let push = push("web", "a");
+            let received = received(&push);
+            batcher
+                .add(Some(tenant), push, received, token)
+                .await
+                .unwrap();
+        }
+        while flushed.lock().unwrap().len() < 12 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        assert!(most.load(Ordering::SeqCst) <= 2);
+        let flushed = flushed.lock().unwrap();
+        for tenant in ["a", "b", "c"] {
+            let tokens: Vec<_> = flushed
+                .iter()
+                .filter(|(t, _)| t.as_deref() == Some(tenant))
+                .map(|(_, token)| *token)
+                .collect();
+            assert!(
+                tokens.is_sorted(),
+                "{tenant} flushed out of order: {tokens:?}"
+            );
+        }
+    }
+}
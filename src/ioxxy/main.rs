@@ -1,12 +1,18 @@
+use axum::body::Body;
 use clap::Parser;
-use iroh::endpoint::ConnectionError;
+use http::{Method, Request, StatusCode};
+use iroh::endpoint::{ConnectionError, ReadToEndError, SendStream};
 use iroh::{Endpoint, SecretKey};
-use oxxy::EXAMPLE_ALPN;
+use oxxy::shapes::{Client, ClientArgs, Format, LokiAuth, LokiAuthArgs};
+use oxxy::{tunnel, EXAMPLE_ALPN};
 use std::process;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Arc;
 use tracing::{debug, info, warn};
 
+// largest framed push accepted from a loxxy node
+const MAX_PUSH_BYTES: usize = 16 * 1024 * 1024;
+
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -19,6 +25,11 @@ struct Args {
 
     #[arg(short, long)]
     shared_secret: Option<String>,
+
+    #[command(flatten)]
+    client: ClientArgs,
+    #[command(flatten)]
+    loki_auth: LokiAuthArgs,
 }
 
 #[tokio::main]
@@ -27,6 +38,10 @@ async fn main() -> Result<(), anyhow::Error> {
     env_logger::init();
     info!("ioxxy gateway starting up...");
 
+    let client = args.client.client()?;
+    let loki_auth = args.loki_auth.auth()?;
+    let loki_url = Arc::new(args.loki_url.clone());
+
     let secret = match args.shared_secret {
         None => SecretKey::generate(rand::rngs::OsRng),
         Some(val) => match SecretKey::from_str(val.as_str()) {
@@ -90,36 +105,102 @@ async fn main() -> Result<(), anyhow::Error> {
         );
 
         // spawn a task to handle reading and writing off of the connection
+        let (client, loki_auth, loki_url) = (client.clone(), loki_auth.clone(), loki_url.clone());
         tokio::spawn(async move {
-            // accept a bi-directional QUIC connection
-            // use the `quinn` APIs to send and recv content
-            let (mut send, mut recv) = conn.accept_bi().await?;
-            debug!("accepted bi stream, waiting for data...");
-            let message = recv.read_to_end(100).await?;
-            let message = String::from_utf8(message)?;
-            println!("received: {message}");
-
-            let message = format!("hi! you connected to {me}. bye bye");
-            send.write_all(message.as_bytes()).await?;
-            // call `finish` to close the connection gracefully
-            send.finish()?;
-
-            // We sent the last message, so wait for the client to close the connection once
-            // it received this message.
-            let res = tokio::time::timeout(Duration::from_secs(3), async move {
-                let closed = conn.closed().await;
-                if !matches!(closed, ConnectionError::ApplicationClosed(_)) {
-                    println!("node {node_id} disconnected with an error: {closed:#}");
+            // every push from loxxy arrives on its own bi-directional QUIC stream and is answered
+            // with the status it was settled with, a failed push only ends its own stream
+            loop {
+                let (mut send, mut recv) = match conn.accept_bi().await {
+                    Ok(streams) => streams,
+                    Err(closed) => {
+                        if !matches!(closed, ConnectionError::ApplicationClosed(_)) {
+                            warn!("node {node_id} disconnected with an error: {closed:#}");
+                        }
+                        break;
+                    }
+                };
+                debug!("accepted bi stream, waiting for data...");
+                let status = match recv.read_to_end(MAX_PUSH_BYTES).await {
+                    Ok(message) => push(&client, &loki_auth, &loki_url, &message).await,
+                    Err(ReadToEndError::TooLong) => {
+                        warn!("dropping push from {node_id} larger than {MAX_PUSH_BYTES} bytes");
+                        let _ = recv.stop(0u32.into());
+                        StatusCode::PAYLOAD_TOO_LARGE
+                    }
+                    Err(e) => {
+                        warn!("unable to read push from {node_id}: {e:#}");
+                        continue;
+                    }
+                };
+                if let Err(e) = reply(&mut send, status).await {
+                    warn!("unable to answer {node_id}: {e:#}");
                 }
-            })
-            .await;
-            if res.is_err() {
-                println!("node {node_id} did not disconnect within 3 seconds");
             }
-            Ok::<_, anyhow::Error>(())
         });
     }
     // stop with SIGINT (ctrl-c)
 
     Ok(())
 }
+
+/// Push a framed message on to Loki, settling it with Loki's answer or why it never got one
+async fn push(client: &Client, loki_auth: &LokiAuth, loki_url: &str, message: &[u8]) -> StatusCode {
+    let (header, body) = match tunnel::decode(message) {
+        Ok(framed) => framed,
+        Err(e) => {
+            warn!("dropping unframed push of {} bytes: {:#}", message.len(), e);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    info!(
+        "received {} bytes for tenant {:?} as {:?}",
+        body.len(),
+        header.tenant,
+        header.content_type
+    );
+    match forward(client, loki_auth, loki_url, header, body).await {
+        Ok(status) if status.is_success() => status,
+        Ok(status) => {
+            warn!("loki answered {} to a push", status);
+            status
+        }
+        Err(e) => {
+            warn!("unable to push to loki: {:#}", e);
+            StatusCode::BAD_GATEWAY
+        }
+    }
+}
+
+async fn reply(send: &mut SendStream, status: StatusCode) -> Result<(), anyhow::Error> {
+    send.write_all(&tunnel::encode_status(status)).await?;
+    // call `finish` to close the stream gracefully
+    send.finish()?;
+    Ok(())
+}
+
+/// Push a framed body on to Loki as loxxy described it
+async fn forward(
+    client: &Client,
+    loki_auth: &LokiAuth,
+    loki_url: &str,
+    header: tunnel::Header,
+    body: &[u8],
+) -> Result<StatusCode, anyhow::Error> {
+    let content_type = header
+        .content_type
+        .unwrap_or_else(|| Format::Json.content_type().to_string());
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(loki_url)
+        .header("content-type", content_type)
+        .header("user-agent", "oxxy-ioxxy");
+    if let Some(encoding) = &header.content_encoding {
+        req = req.header("content-encoding", encoding);
+    }
+    let req = loki_auth
+        .apply(req, header.tenant.as_deref())
+        .body(Body::from(body.to_vec()))?;
+    let status = client.request(req).await?.status();
+    debug!("loki answered {} to {} bytes", status, body.len());
+    Ok(status)
+}
@@ -1,4 +1,10 @@
+pub mod amqp;
+pub mod batch;
+pub mod logproto;
+pub mod retry;
 pub mod shapes;
+pub mod template;
+pub mod tunnel;
 
 // An example ALPN that we are using to communicate over the `Endpoint`
 pub const EXAMPLE_ALPN: &[u8] = b"neiam/oxxy/logger/0";
@@ -0,0 +1,204 @@
+//! Loki's `logproto.PushRequest` wire format, snappy compressed protobuf
+
+use anyhow::anyhow;
+use prost::Message;
+
+use crate::shapes;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PushRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub streams: Vec<StreamAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StreamAdapter {
+    /// label set in LogQL selector form, `{app="foo", level="info"}`
+    #[prost(string, tag = "1")]
+    pub labels: String,
+    #[prost(message, repeated, tag = "2")]
+    pub entries: Vec<EntryAdapter>,
+    #[prost(uint64, tag = "3")]
+    pub hash: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EntryAdapter {
+    #[prost(message, optional, tag = "1")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    pub line: String,
+    #[prost(message, repeated, tag = "3")]
+    pub structured_metadata: Vec<LabelPairAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelPairAdapter {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+/// `google.protobuf.Timestamp`
+#[derive(Clone, Copy, PartialEq, Message)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+impl PushRequest {
+    /// Decode a snappy (block format) compressed push body, refusing one that claims to expand
+    /// past [`shapes::MAX_DECODED_BYTES`] before allocating for it
+    pub fn decode_snappy(body: &[u8]) -> Result<Self, anyhow::Error> {
+        let len = snap::raw::decompress_len(body)?;
+        if len > shapes::MAX_DECODED_BYTES {
+            return Err(anyhow!(
+                "snappy body decodes to {len} bytes, more than {}",
+                shapes::MAX_DECODED_BYTES
+            ));
+        }
+        let raw = snap::raw::Decoder::new().decompress_vec(body)?;
+        Ok(PushRequest::decode(raw.as_slice())?)
+    }
+
+    pub fn encode_snappy(&self) -> Result<Vec<u8>, anyhow::Error> {
+        Ok(snap::raw::Encoder::new().compress_vec(&self.encode_to_vec())?)
+    }
+}
+
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+
+impl From<&shapes::PushRequest> for PushRequest {
+    fn from(push: &shapes::PushRequest) -> Self {
+        let streams = push
+            .streams
+            .iter()
+            .map(|stream| StreamAdapter {
+                labels: stream.labels.to_string(),
+                entries: stream.values.iter().map(EntryAdapter::from).collect(),
+                hash: 0,
+            })
+            .collect();
+        PushRequest { streams }
+    }
+}
+
+impl From<&shapes::Entry> for EntryAdapter {
+    fn from(entry: &shapes::Entry) -> Self {
+        let nanos = entry.timestamp.nanos();
+        EntryAdapter {
+            timestamp: Some(Timestamp {
+                seconds: nanos.div_euclid(NANOS_PER_SECOND),
+                nanos: nanos.rem_euclid(NANOS_PER_SECOND) as i32,
+            }),
+            line: entry.line.clone(),
+            structured_metadata: entry
+                .structured_metadata
+                .iter()
+                .map(|(name, value)| LabelPairAdapter {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<PushRequest> for shapes::PushRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(push: PushRequest) -> Result<Self, Self::Error> {
+        let streams = push
+            .streams
+            .into_iter()
+            .map(|stream| {
+                Ok(shapes::Stream {
+                    labels: stream.labels.parse()?,
+                    values: stream
+                        .entries
+                        .into_iter()
+                        .map(shapes::Entry::try_from)
+                        .collect::<Result<_, _>>()?,
+                })
+            })
+            .collect::<Result<_, anyhow::Error>>()?;
+        Ok(shapes::PushRequest { streams })
+    }
+}
+
+impl TryFrom<EntryAdapter> for shapes::Entry {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: EntryAdapter) -> Result<Self, Self::Error> {
+        let timestamp = entry.timestamp.unwrap_or_default();
+        let nanos = timestamp
+            .seconds
+            .checked_mul(NANOS_PER_SECOND)
+            .and_then(|nanos| nanos.checked_add(timestamp.nanos as i64))
+            .ok_or_else(|| anyhow!("timestamp {}s out of range", timestamp.seconds))?;
+        Ok(shapes::Entry {
+            timestamp: shapes::Timestamp::from_nanos(nanos),
+            line: entry.line,
+            structured_metadata: entry
+                .structured_metadata
+                .into_iter()
+                .map(|pair| (pair.name, pair.value))
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_snappy_bodies() {
+        let push = PushRequest {
+            streams: vec![StreamAdapter {
+                labels: "{app=\"x\"}".to_string(),
+                ..Default::default()
+            }],
+        };
+        let body = push.encode_snappy().unwrap();
+        assert_eq!(PushRequest::decode_snappy(&body).unwrap(), push);
+
+        // only the varint length preamble, claiming one byte over the limit
+        let mut bomb = vec![];
+        let mut len = shapes::MAX_DECODED_BYTES as u64 + 1;
+        while len >= 0x80 {
+            bomb.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        bomb.push(len as u8);
+        let err = PushRequest::decode_snappy(&bomb).unwrap_err();
+        assert!(err.to_string().contains("more than"));
+    }
+
+    #[test]
+    fn refuses_overflowing_timestamps() {
+        let entry = |seconds| EntryAdapter {
+            timestamp: Some(Timestamp { seconds, nanos: 5 }),
+            ..Default::default()
+        };
+        let parsed = shapes::Entry::try_from(entry(1)).unwrap();
+        assert_eq!(
+            parsed.timestamp,
+            shapes::Timestamp::from_nanos(1_000_000_005)
+        );
+        assert!(shapes::Entry::try_from(entry(i64::MAX)).is_err());
+        assert!(shapes::Entry::try_from(entry(i64::MAX / NANOS_PER_SECOND + 1)).is_err());
+
+        let push = PushRequest {
+            streams: vec![StreamAdapter {
+                labels: "{app=\"x\"}".to_string(),
+                entries: vec![entry(i64::MAX)],
+                hash: 0,
+            }],
+        };
+        assert!(shapes::PushRequest::try_from(push).is_err());
+    }
+}
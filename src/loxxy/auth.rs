@@ -0,0 +1,250 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header, HeaderMap, HeaderValue};
+use hyper::StatusCode;
+use tracing::debug;
+
+use crate::mtls::{self, ClientCert};
+use crate::policy::LabelPolicy;
+use crate::users;
+use crate::{Authentication, Statey};
+
+/// The caller a request was authenticated as, stashed in the request extensions
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub user: String,
+    pub tenant: Option<String>,
+    /// extra `(name, value)` labels the identity carries into its streams and is confined to on reads
+    pub labels: Vec<(String, String)>,
+    /// label rules for the identity's pushes, `None` for loxxy's defaults
+    pub policy: Option<LabelPolicy>,
+}
+
+impl Identity {
+    fn anonymous() -> Self {
+        Identity {
+            user: "anonymous".to_string(),
+            tenant: None,
+            labels: vec![],
+            policy: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Credentials {
+    Basic { user: String, password: String },
+    Bearer(String),
+}
+
+fn credentials(headers: &HeaderMap) -> Option<Credentials> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, rest) = value.split_once(' ')?;
+    let rest = rest.trim();
+    if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(STANDARD.decode(rest).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some(Credentials::Basic {
+            user: user.to_string(),
+            password: password.to_string(),
+        })
+    } else if scheme.eq_ignore_ascii_case("bearer") {
+        Some(Credentials::Bearer(rest.to_string()))
+    } else {
+        None
+    }
+}
+
+// compare secrets without bailing out on the first differing byte
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unauthorized(auth: &Authentication) -> Response {
+    let challenge = match auth {
+        Authentication::Oauth => "Bearer realm=\"loxxy\"",
+        // there is no challenge for a certificate the handshake didn't carry
+        Authentication::Mtls => return StatusCode::UNAUTHORIZED.into_response(),
+        _ => "Basic realm=\"loxxy\"",
+    };
+    (
+        StatusCode::UNAUTHORIZED,
+        [(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static(challenge),
+        )],
+    )
+        .into_response()
+}
+
+async fn basic(state: &Statey, user: String, password: String) -> Option<Identity> {
+    if let Some(users) = &state.users {
+        let entry = users.read().unwrap().get(&user).cloned();
+        let verified = {
+            let entry = entry.clone();
+            tokio::task::spawn_blocking(move || users::verify(entry.as_ref(), &password))
+                .await
+                .unwrap_or(false)
+        };
+        let entry = entry.filter(|_| verified)?;
+        return Some(Identity {
+            user,
+            tenant: entry.tenant,
+            labels: entry.labels.into_iter().collect(),
+            policy: entry.policy,
+        });
+    }
+    match (&state.args.user, &state.args.token) {
+        (Some(expected_user), Some(expected_token))
+            if constant_time_eq(user.as_bytes(), expected_user.as_bytes())
+                && constant_time_eq(password.as_bytes(), expected_token.as_bytes()) =>
+        {
+            Some(Identity {
+                user,
+                tenant: None,
+                labels: vec![],
+                policy: None,
+            })
+        }
+        _ => None,
+    }
+}
+
+async fn rabbit(state: &Statey, user: String, password: String) -> Option<Identity> {
+    let rabbit = state.rabbit.as_ref()?;
+    rabbit.login(&user, &password).await.then_some(Identity {
+        user,
+        tenant: None,
+        labels: vec![],
+        policy: None,
+    })
+}
+
+async fn bearer(state: &Statey, token: &str) -> Option<Identity> {
+    if let Some(jwks) = &state.jwks {
+        return jwks
+            .validate(token, &state.oauth)
+            .await
+            .map_err(|e| debug!("rejecting bearer token: {:#}", e))
+            .ok();
+    }
+    match (&state.args.user, &state.args.token) {
+        (Some(expected_user), Some(expected_token))
+            if constant_time_eq(token.as_bytes(), expected_token.as_bytes()) =>
+        {
+            Some(Identity {
+                user: expected_user.clone(),
+                tenant: None,
+                labels: vec![],
+                policy: None,
+            })
+        }
+        _ => None,
+    }
+}
+
+// the handshake already verified the certificate, the users file only maps and restricts it
+fn mtls(state: &Statey, req: &Request) -> Option<Identity> {
+    let ConnectInfo(ClientCert(cert)) = req.extensions().get::<ConnectInfo<ClientCert>>()?;
+    let user = mtls::subject(cert.as_ref()?, state.args.mtls_identity)
+        .map_err(|e| debug!("rejecting client certificate: {:#}", e))
+        .ok()?;
+    let mut identity = match &state.users {
+        Some(users) => {
+            let entry = users.read().unwrap().get(&user).cloned()?;
+            Identity {
+                user,
+                tenant: entry.tenant,
+                labels: entry.labels.into_iter().collect(),
+                policy: entry.policy,
+            }
+        }
+        None => Identity {
+            user,
+            tenant: None,
+            labels: vec![],
+            policy: None,
+        },
+    };
+    if let Some(label) = &state.args.mtls_label {
+        identity.labels.push((label.clone(), identity.user.clone()));
+    }
+    Some(identity)
+}
+
+/// Middleware checking every request against the configured `Authentication` mode
+pub async fn authenticate(State(state): State<Statey>, mut req: Request, next: Next) -> Response {
+    let args = &state.args;
+    let identity = match (&args.auth, credentials(req.headers())) {
+        (Authentication::None, _) => Some(Identity::anonymous()),
+        (Authentication::Basic, Some(Credentials::Basic { user, password })) => {
+            basic(&state, user, password).await
+        }
+        (Authentication::Rabbit, Some(Credentials::Basic { user, password })) => {
+            rabbit(&state, user, password).await
+        }
+        (Authentication::Oauth, Some(Credentials::Bearer(token))) => bearer(&state, &token).await,
+        (Authentication::Mtls, _) => mtls(&state, &req),
+        (auth, _) => {
+            debug!("credentials missing or not usable for {:?} auth", auth);
+            None
+        }
+    };
+
+    match identity {
+        Some(identity) => {
+            debug!(
+                "authenticated {} for {} with labels {:?}",
+                identity.user,
+                req.uri(),
+                identity.labels
+            );
+            req.extensions_mut().insert(identity);
+            next.run(req).await
+        }
+        None => {
+            debug!("rejecting unauthenticated request to {}", req.uri());
+            unauthorized(&args.auth)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(authorization).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn reads_credentials() {
+        let basic = format!("basic {}", STANDARD.encode("alice:pass:word"));
+        match credentials(&headers(&basic)) {
+            Some(Credentials::Basic { user, password }) => {
+                assert_eq!((user.as_str(), password.as_str()), ("alice", "pass:word"))
+            }
+            other => panic!("expected basic credentials, got {other:?}"),
+        }
+        assert!(matches!(
+            credentials(&headers("Bearer  abc.def ")),
+            Some(Credentials::Bearer(token)) if token == "abc.def"
+        ));
+        assert!(credentials(&headers("Basic not-base64!")).is_none());
+        assert!(credentials(&headers("Digest abc")).is_none());
+        assert!(credentials(&HeaderMap::new()).is_none());
+
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+}
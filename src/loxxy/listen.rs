@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Context};
+use rustls::server::{danger::ClientCertVerifier, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{debug, info, warn};
+
+/// An address loxxy accepts requests on, `0.0.0.0:4000`, `[::]:4000` or `unix:/run/loxxy.sock`
+#[derive(Debug, Clone)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+pub fn parse_listen(s: &str) -> Result<Listen, String> {
+    match s.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => Ok(Listen::Unix(PathBuf::from(path))),
+        #[cfg(not(unix))]
+        Some(_) => Err("unix sockets are not supported on this platform".to_string()),
+        None => s
+            .parse()
+            .map(Listen::Tcp)
+            .map_err(|e| format!("expected ip:port or unix:path, got {s}: {e}")),
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Remove the socket a previous run left at `path`, refusing to touch anything that isn't a socket
+#[cfg(unix)]
+pub fn remove_stale_socket(path: &std::path::Path) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("removing stale socket {}", path.display())),
+        Ok(_) => Err(anyhow!("{} exists and is not a socket", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("checking {}", path.display())),
+    }
+}
+
+/// The certificate and key served for TLS, swapped out whenever either file changes
+#[derive(Debug)]
+pub struct ReloadingCert {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCert {
+    pub fn load(cert: PathBuf, key: PathBuf) -> Result<Arc<Self>, anyhow::Error> {
+        let current = RwLock::new(Arc::new(certified_key(&cert, &key)?));
+        info!("loaded tls certificate {}", cert.display());
+        Ok(Arc::new(ReloadingCert { cert, key, current }))
+    }
+
+    /// Check the files every `interval`, keeping the served pair when the new one doesn't load
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let certs = self.clone();
+        tokio::spawn(async move {
+            let mut seen = certs.modified();
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let modified = certs.modified();
+                if modified == seen {
+                    continue;
+                }
+                seen = modified;
+                match certified_key(&certs.cert, &certs.key) {
+                    Ok(loaded) => {
+                        info!("reloaded tls certificate {}", certs.cert.display());
+                        *certs.current.write().unwrap() = Arc::new(loaded);
+                    }
+                    Err(e) => warn!("keeping previous certificate, reload failed: {:#}", e),
+                }
+            }
+        });
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert), modified(&self.key))
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn certified_key(cert: &PathBuf, key: &PathBuf) -> Result<CertifiedKey, anyhow::Error> {
+    let raw = std::fs::read(cert).with_context(|| format!("reading {}", cert.display()))?;
+    let chain = rustls_pemfile::certs(&mut raw.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if chain.is_empty() {
+        return Err(anyhow!("no certificates in {}", cert.display()));
+    }
+    let raw = std::fs::read(key).with_context(|| format!("reading {}", key.display()))?;
+    let key = rustls_pemfile::private_key(&mut raw.as_slice())?
+        .ok_or_else(|| anyhow!("no private key in {}", key.display()))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+    Ok(CertifiedKey::new(chain, key))
+}
+
+pub fn server_config(
+    certs: Arc<ReloadingCert>,
+    clients: Option<Arc<dyn ClientCertVerifier>>,
+) -> Result<ServerConfig, anyhow::Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder =
+        ServerConfig::builder_with_provider(provider).with_safe_default_protocol_versions()?;
+    let builder = match clients {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(certs);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A TCP listener handing out connections once their TLS handshake is done
+///
+/// Handshakes run on their own tasks so a slow client can't hold up the accept loop.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    incoming: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, incoming) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("accepting on {} failed: {}", local_addr, e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, peer)).await;
+                        }
+                        Ok(Err(e)) => debug!("tls handshake with {} failed: {}", peer, e),
+                        Err(_) => debug!("tls handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener {
+            local_addr,
+            incoming,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(accepted) => accepted,
+            // the accept loop never ends while the listener is alive
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
@@ -0,0 +1,208 @@
+use anyhow::anyhow;
+use oxxy::shapes::Labels;
+
+/// Add `matchers` to every stream selector of a LogQL query
+///
+/// Selectors are ANDed, so a client can narrow the injected matchers but never widen them. Queries
+/// that don't scan cleanly, or that have no selector to scope, are refused rather than guessed at.
+pub fn scope(query: &str, matchers: &[(String, String)]) -> Result<String, anyhow::Error> {
+    let forced = selector(matchers);
+    // `{a="b", c="d"}` without its braces
+    let forced = &forced[1..forced.len() - 1];
+
+    let mut scoped = String::with_capacity(query.len() + forced.len());
+    let mut selectors = 0;
+    let mut chars = query.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '`' => {
+                scoped.push(c);
+                quoted(c, &mut chars, &mut scoped)?;
+            }
+            '#' => {
+                // comments run to the end of the line
+                scoped.push(c);
+                for c in chars.by_ref() {
+                    scoped.push(c);
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '{' => {
+                let mut inner = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c @ ('"' | '`')) => {
+                            inner.push(c);
+                            quoted(c, &mut chars, &mut inner)?;
+                        }
+                        Some('{') => return Err(anyhow!("nested braces in selector")),
+                        // a comment would swallow the matchers appended after it
+                        Some('#') => return Err(anyhow!("comment in selector")),
+                        Some(c) => inner.push(c),
+                        None => return Err(anyhow!("unterminated selector")),
+                    }
+                }
+                // newlines are kept, a line break may be all that ends something before it
+                let inner = inner.trim_matches([' ', '\t']);
+                scoped.push('{');
+                scoped.push_str(inner);
+                if !inner.is_empty() {
+                    scoped.push_str(", ");
+                }
+                scoped.push_str(forced);
+                scoped.push('}');
+                selectors += 1;
+            }
+            '}' => return Err(anyhow!("unbalanced braces")),
+            c => scoped.push(c),
+        }
+    }
+    if selectors == 0 {
+        return Err(anyhow!("query has no stream selector"));
+    }
+    Ok(scoped)
+}
+
+/// Whether a `Content-Type` is a urlencoded form, compared the way Loki parses media types:
+/// case-insensitively and without parameters
+pub fn is_form(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default();
+    media_type
+        .trim()
+        .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+}
+
+/// The parameter a Loki read endpoint takes its stream selectors in, `None` for anything else
+pub fn selector_param(path: &str) -> Option<&'static str> {
+    match path.strip_prefix("/loki/api/v1/")? {
+        "query" | "query_range" | "tail" | "labels" => Some("query"),
+        "series" => Some("match[]"),
+        endpoint if endpoint.starts_with("label/") && endpoint.ends_with("/values") => {
+            Some("query")
+        }
+        _ => None,
+    }
+}
+
+/// Scope the selectors in a urlencoded parameter list, adding one when the client sent none
+pub fn scope_params(
+    params: &str,
+    name: &str,
+    matchers: &[(String, String)],
+) -> Result<String, anyhow::Error> {
+    let mut scoped = form_urlencoded::Serializer::new(String::new());
+    let mut found = false;
+    for (key, value) in form_urlencoded::parse(params.as_bytes()) {
+        // loki takes `match` as well as `match[]`
+        if key == name || (name == "match[]" && key == "match") {
+            found = true;
+            if value.trim().is_empty() {
+                scoped.append_pair(&key, &selector(matchers));
+            } else {
+                scoped.append_pair(&key, &scope(&value, matchers)?);
+            }
+        } else {
+            scoped.append_pair(&key, &value);
+        }
+    }
+    if !found {
+        scoped.append_pair(name, &selector(matchers));
+    }
+    Ok(scoped.finish())
+}
+
+fn selector(matchers: &[(String, String)]) -> String {
+    matchers.iter().cloned().collect::<Labels>().to_string()
+}
+
+// copy a string literal through its closing quote, `"` strings take backslash escapes
+fn quoted(quote: char, chars: &mut std::str::Chars, out: &mut String) -> Result<(), anyhow::Error> {
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if c == quote {
+            return Ok(());
+        }
+        if c == '\\' && quote == '"' {
+            out.push(chars.next().ok_or_else(|| anyhow!("unterminated string"))?);
+        }
+    }
+    Err(anyhow!("unterminated string"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn team() -> Vec<(String, String)> {
+        vec![("team".to_string(), "a".to_string())]
+    }
+
+    #[test]
+    fn recognises_forms() {
+        assert!(is_form("application/x-www-form-urlencoded"));
+        assert!(is_form(
+            " Application/X-WWW-Form-Urlencoded ; charset=UTF-8"
+        ));
+        assert!(!is_form("application/json"));
+        assert!(!is_form("application/x-www-form-urlencodedx"));
+    }
+
+    #[test]
+    fn scopes_every_selector() {
+        assert_eq!(
+            scope(r#"{app="web"} |= "error""#, &team()).unwrap(),
+            r#"{app="web", team="a"} |= "error""#
+        );
+        assert_eq!(
+            scope(
+                r#"sum(rate({app="web"}[5m])) / sum(rate({ app=~"w.*" }[5m]))"#,
+                &team()
+            )
+            .unwrap(),
+            r#"sum(rate({app="web", team="a"}[5m])) / sum(rate({app=~"w.*", team="a"}[5m]))"#
+        );
+        assert_eq!(scope("{}", &team()).unwrap(), r#"{team="a"}"#);
+    }
+
+    #[test]
+    fn leaves_strings_and_comments_alone() {
+        let query = r#"{app="}"} | line_format "{{.msg}}" |= `{` # {x="y"}"#;
+        assert_eq!(
+            scope(query, &team()).unwrap(),
+            r#"{app="}", team="a"} | line_format "{{.msg}}" |= `{` # {x="y"}"#
+        );
+        assert_eq!(
+            scope(r#"{app="a\"}"}"#, &team()).unwrap(),
+            r#"{app="a\"}", team="a"}"#
+        );
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_scope() {
+        assert!(scope("vector(1)", &team()).is_err());
+        assert!(scope(r#"{app="web""#, &team()).is_err());
+        assert!(scope(r#"{app="web"}}"#, &team()).is_err());
+        assert!(scope(r#"{app={"web"}}"#, &team()).is_err());
+        assert!(scope(r#"{app="web}"#, &team()).is_err());
+        assert!(scope("{app=\"x\" #\n}\"\n} |= \"a\\\" |= \"", &team()).is_err());
+    }
+
+    #[test]
+    fn scopes_parameters() {
+        let params = scope_params("query=%7Bapp%3D%22web%22%7D&limit=10", "query", &team());
+        assert_eq!(
+            params.unwrap(),
+            "query=%7Bapp%3D%22web%22%2C+team%3D%22a%22%7D&limit=10"
+        );
+        let params = scope_params("start=1", "match[]", &team());
+        assert_eq!(params.unwrap(), "start=1&match%5B%5D=%7Bteam%3D%22a%22%7D");
+        assert_eq!(
+            selector_param("/loki/api/v1/label/app/values"),
+            Some("query")
+        );
+        assert_eq!(selector_param("/loki/api/v1/push"), None);
+    }
+}
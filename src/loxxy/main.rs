@@ -1,36 +1,66 @@
+mod auth;
+mod listen;
+mod logql;
+mod mtls;
+mod oauth;
+mod policy;
+mod push;
+mod rabbit;
+mod users;
+
 use axum::{
-    body::Body,
-    extract::{Request, State},
-    http::uri::Uri,
+    body::{Body, Bytes},
+    extract::{Extension, Request, State},
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+        uri::Uri,
+        HeaderMap, HeaderValue,
+    },
+    middleware,
     response::{IntoResponse, Response},
-    routing::post,
+    routing::{get, post},
     Router as AxumRouter,
 };
 use clap::Parser;
 use clap_derive::{Subcommand, ValueEnum};
-use http_body_util::BodyExt;
-use hyper::StatusCode;
-use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
-use lapin::options::{BasicPublishOptions, ExchangeDeclareOptions};
-use lapin::types::FieldTable;
-use lapin::{
-    BasicProperties, Channel, Connection as LapinConnection, ConnectionProperties, ExchangeKind,
-};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::{Method, StatusCode, Version};
+use hyper_util::rt::TokioIo;
+use lapin::options::BasicPublishOptions;
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{BasicProperties, Channel, Connection as LapinConnection, ConnectionProperties};
 
 use anyhow::Context;
-use oxxy::shapes::Client;
+use auth::Identity;
+use oxxy::amqp::ExchangeArgs;
+use oxxy::shapes::{
+    Client, ClientArgs, Labels, LokiAuth, LokiAuthArgs, OutputFormat, Payload, ORG_ID_HEADER,
+};
+use oxxy::template::{Template, AMQP_RESERVED, MQTT_RESERVED};
+use oxxy::tunnel;
 use paho_mqtt as mqtt;
-use paho_mqtt::Message;
+use std::path::PathBuf;
 use std::process::exit;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::copy_bidirectional;
+use tokio::net::TcpListener;
 use tokio::sync::Mutex;
-use tracing::{debug, info};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
 
 use iroh::endpoint::Connection;
 #[cfg(feature = "iroh-support")]
 use iroh::Endpoint;
 use iroh::{NodeAddr, SecretKey};
+use listen::{parse_listen, Listen, ReloadingCert, TlsListener};
+use mtls::{CertName, ClientCert};
+use oauth::{Jwks, OauthPolicy, SharedJwks};
 use oxxy::EXAMPLE_ALPN;
+use policy::{Forbidden, LabelPolicy};
+use rabbit::RabbitAuth;
+use users::{SharedUsers, Users};
 // #[cfg(feature = "iroh-support")]
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -39,6 +69,18 @@ enum Authentication {
     Basic,
     Rabbit,
     Oauth,
+    Mtls,
+}
+
+/// What to do with an `X-Scope-OrgID` the client sent itself
+#[derive(Debug, Clone, ValueEnum)]
+enum OrgIdMode {
+    /// replace it with the tenant loxxy worked out
+    Override,
+    /// refuse the request when it names a different tenant
+    Reject,
+    /// pass it on when loxxy worked out no tenant itself, only for clients trusted to pick theirs
+    Trust,
 }
 
 #[derive(Clone, Debug, Subcommand)]
@@ -57,8 +99,13 @@ enum Commands {
         #[clap(short, long, default_value = "logs")]
         queue: String,
 
-        #[clap(long, default_value = "logs")]
-        routing_key: String,
+        /// Routing key rendered per stream from its labels, `{tenant}` and `{user}`, such as
+        /// `logs.{tenant}.{hostname}.{level}`; the queue name when unset
+        #[clap(long)]
+        routing_key: Option<Template>,
+
+        #[command(flatten)]
+        topology: ExchangeArgs,
     },
     MQTT {
         #[clap(short, long)]
@@ -67,10 +114,14 @@ enum Commands {
         user: Option<String>,
         #[arg(short, long)]
         token: Option<String>,
+        /// Topic rendered per stream like `--routing-key`, such as `{tenant}/{hostname}/{app}/logs`
         #[arg(long)]
-        topic: String,
+        topic: Template,
         #[arg(short, long, default_value = "0")]
         qos: usize,
+        /// Connect with MQTT v5 and carry the tenant as a user property
+        #[arg(long)]
+        v5: bool,
     },
     #[cfg(feature = "iroh-support")]
     IROH {
@@ -87,6 +138,12 @@ struct Args {
     #[command(subcommand)]
     cmd: Commands,
 
+    #[command(flatten)]
+    client: ClientArgs,
+
+    #[command(flatten)]
+    loki_auth: LokiAuthArgs,
+
     #[arg(short, long)]
     auth: Authentication,
 
@@ -95,12 +152,130 @@ struct Args {
     user: Option<String>,
     #[arg(short, long)]
     token: Option<String>,
+
+    /// Loki tenant sent as X-Scope-OrgID when the authenticated user doesn't carry one
+    #[arg(long, env = "LOXXY_ORG_ID")]
+    org_id: Option<String>,
+    #[arg(
+        long,
+        env = "LOXXY_ORG_ID_MODE",
+        value_enum,
+        default_value = "override"
+    )]
+    org_id_mode: OrgIdMode,
+
+    /// Label set on every pushed stream as `key=value`, repeatable
+    #[arg(
+        long = "label",
+        env = "LOXXY_LABELS",
+        value_delimiter = ',',
+        value_parser = parse_pair
+    )]
+    labels: Vec<(String, String)>,
+    /// Label clients may not set themselves, repeatable; users file policies take precedence
+    #[arg(
+        long = "forbid-label",
+        env = "LOXXY_FORBIDDEN_LABELS",
+        value_delimiter = ','
+    )]
+    forbidden_labels: Vec<String>,
+    #[arg(long, value_enum, default_value = "strip")]
+    on_forbidden_label: Forbidden,
+    /// Most labels a pushed stream may carry
+    #[arg(long)]
+    max_labels: Option<usize>,
+    /// Largest request body taken from a client, in bytes
+    #[arg(long, env = "LOXXY_MAX_BODY_BYTES", default_value = "16777216")]
+    max_body_bytes: usize,
+    /// Re-encode pushes before forwarding or publishing them
+    #[arg(
+        long,
+        env = "LOXXY_OUTPUT_FORMAT",
+        value_enum,
+        default_value = "passthrough"
+    )]
+    output_format: OutputFormat,
+
+    /// TOML file of users with password hashes and tenants, reloaded on SIGHUP
+    #[arg(long)]
+    users_file: Option<PathBuf>,
+
+    /// JWKS file or url used to verify bearer tokens with `--auth oauth`
+    #[arg(long)]
+    jwks: Option<String>,
+    /// Seconds between JWKS refreshes
+    #[arg(long, default_value = "300")]
+    jwks_refresh: u64,
+    #[arg(long)]
+    oauth_issuer: Option<String>,
+    #[arg(long)]
+    oauth_audience: Option<String>,
+    /// Token claim holding the tenant sent to Loki as X-Scope-OrgID
+    #[arg(long)]
+    tenant_claim: Option<String>,
+    /// Copy a token claim into the stream labels as `claim=label`, repeatable
+    #[arg(long, value_parser = parse_pair)]
+    claim_label: Vec<(String, String)>,
+
+    /// Broker to log clients into with `--auth rabbit`, defaults to the AMQP backend's uri
+    #[arg(long)]
+    rabbit_uri: Option<String>,
+    /// Seconds a successful rabbit login is remembered
+    #[arg(long, default_value = "300")]
+    rabbit_cache_ttl: u64,
+
+    /// Address to serve on, `ip:port` or `unix:path`, repeatable
+    #[arg(
+        long,
+        env = "LOXXY_LISTEN",
+        value_delimiter = ',',
+        value_parser = parse_listen,
+        default_value = "0.0.0.0:4000"
+    )]
+    listen: Vec<Listen>,
+    /// PEM certificate chain to terminate TLS with on TCP listeners, reloaded when it changes
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Seconds between checks of the certificate and key for changes
+    #[arg(long, default_value = "30")]
+    tls_reload: u64,
+    /// CA bundle client certificates are verified against, required with `--auth mtls`
+    #[arg(long, requires = "tls_cert")]
+    client_ca: Option<PathBuf>,
+    /// Certificate field the user is named by with `--auth mtls`
+    #[arg(long, value_enum, default_value = "cn")]
+    mtls_identity: CertName,
+    /// Label set to the certificate's user name on every pushed stream
+    #[arg(long)]
+    mtls_label: Option<String>,
+}
+
+fn parse_pair(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected key=value, got {s}"))
+}
+
+fn rabbit_uri(args: &Args) -> Option<&str> {
+    match (&args.rabbit_uri, &args.cmd) {
+        (Some(uri), _) => Some(uri),
+        (None, Commands::AMQP { rmq_uri, .. }) => Some(rmq_uri),
+        _ => None,
+    }
 }
 
 #[derive(Clone)]
 pub struct Statey {
     args: Args,
     client: Client,
+    loki_auth: LokiAuth,
+    users: Option<SharedUsers>,
+    jwks: Option<SharedJwks>,
+    oauth: OauthPolicy,
+    policy: LabelPolicy,
+    rabbit: Option<Arc<RabbitAuth>>,
     amqp: Option<Channel>,
     mqtt: Option<mqtt::AsyncClient>,
     #[cfg(feature = "iroh-support")]
@@ -115,25 +290,76 @@ async fn main() -> Result<(), anyhow::Error> {
     // check that auth has been passed in
     match &args.auth {
         Authentication::None => {}
-        Authentication::Basic | Authentication::Oauth | Authentication::Rabbit => {
-            match (&args.user, &args.token) {
-                (Some(_username), Some(_token)) => {}
-                _ => {
-                    info!("Authentication details, required.");
-                    exit(1)
-                }
+        Authentication::Basic if args.users_file.is_some() => {}
+        Authentication::Oauth if args.jwks.is_some() => {}
+        Authentication::Mtls if args.client_ca.is_some() => {}
+        Authentication::Mtls => {
+            info!("Mtls authentication needs --client-ca, --tls-cert and --tls-key.");
+            exit(1)
+        }
+        Authentication::Rabbit => {
+            if rabbit_uri(&args).is_none() {
+                info!("Rabbit authentication needs --rabbit-uri or the AMQP backend.");
+                exit(1)
             }
         }
+        Authentication::Basic | Authentication::Oauth => match (&args.user, &args.token) {
+            (Some(_username), Some(_token)) => {}
+            _ => {
+                info!("Authentication details, required.");
+                exit(1)
+            }
+        },
     }
 
     info!("Starting Loxxy with args {:?}", args);
-    let client: Client =
-        hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
-            .build(HttpConnector::new());
+    let client = args.client.client()?;
+    let loki_auth = args.loki_auth.auth()?;
+    let users = match &args.users_file {
+        Some(path) => {
+            let loaded: SharedUsers = Arc::new(RwLock::new(Users::load(path)?));
+            #[cfg(unix)]
+            users::reload_on_sighup(path.clone(), loaded.clone())?;
+            Some(loaded)
+        }
+        None => None,
+    };
+    let jwks = match &args.jwks {
+        Some(source) => {
+            let jwks = Jwks::load(source.clone(), client.clone()).await?;
+            jwks.refresh_every(Duration::from_secs(args.jwks_refresh));
+            Some(jwks)
+        }
+        None => None,
+    };
+    let oauth = OauthPolicy {
+        issuer: args.oauth_issuer.clone(),
+        audience: args.oauth_audience.clone(),
+        tenant_claim: args.tenant_claim.clone(),
+        claim_labels: args.claim_label.clone(),
+    };
+    let policy = LabelPolicy {
+        forbidden: args.forbidden_labels.clone(),
+        on_forbidden: args.on_forbidden_label,
+        max_labels: args.max_labels,
+    };
+    let rabbit = match (&args.auth, rabbit_uri(&args)) {
+        (Authentication::Rabbit, Some(uri)) => Some(Arc::new(RabbitAuth::new(
+            uri,
+            Duration::from_secs(args.rabbit_cache_ttl),
+        )?)),
+        _ => None,
+    };
     let argsc = args.clone();
     let mut state = Statey {
         args: argsc,
         client,
+        loki_auth,
+        users,
+        jwks,
+        oauth,
+        policy,
+        rabbit,
         amqp: None,
         mqtt: None,
         #[cfg(feature = "iroh-support")]
@@ -144,22 +370,22 @@ async fn main() -> Result<(), anyhow::Error> {
         Commands::AMQP {
             rmq_uri,
             exchange,
-            queue: _,
-            routing_key: _,
+            topology,
+            ..
         } => {
             let options = ConnectionProperties::default();
-            let connection = LapinConnection::connect(rmq_uri, options).await.unwrap();
-            let channel = connection.create_channel().await.unwrap();
-            channel
-                .exchange_declare(
-                    exchange,                          // exchange name
-                    ExchangeKind::Direct,              // type of exchange
-                    ExchangeDeclareOptions::default(), // default options
-                    FieldTable::default(),             // no extra parameters
-                )
+            let connection = LapinConnection::connect(rmq_uri, options)
+                .await
+                .context("connecting to rabbitmq")?;
+            let channel = connection
+                .create_channel()
                 .await
-                .expect("Failed to declare channel");
-            info!("mqtt connected");
+                .context("opening a rabbitmq channel")?;
+            topology
+                .declare_exchange(&channel, exchange)
+                .await
+                .with_context(|| format!("declaring exchange {exchange}"))?;
+            info!("amqp connected");
             state.amqp = Some(channel);
         }
         Commands::MQTT {
@@ -168,23 +394,32 @@ async fn main() -> Result<(), anyhow::Error> {
             token,
             topic: _,
             qos: _,
+            v5,
         } => {
             let create_opts = mqtt::CreateOptionsBuilder::new()
                 .server_uri(mqtt_uri)
                 .client_id("oxxy-toxxy") // Set a client ID for your connection
+                .mqtt_version(match v5 {
+                    true => mqtt::MQTT_VERSION_5,
+                    false => mqtt::MQTT_VERSION_DEFAULT,
+                })
                 .finalize();
             let cli = mqtt::AsyncClient::new(create_opts)?;
 
-            let conn_opts = match (user, token) {
-                (Some(user), Some(token)) => {
-                    mqtt::ConnectOptionsBuilder::new()
-                        .user_name(user) // Set the username
-                        .password(token) // Set the password
-                        .clean_session(true) // Set clean session
-                        .finalize()
-                }
-                _ => mqtt::ConnectOptionsBuilder::new().finalize(),
+            let mut conn_opts = match v5 {
+                true => mqtt::ConnectOptionsBuilder::new_v5(),
+                false => mqtt::ConnectOptionsBuilder::new(),
             };
+            if let (Some(user), Some(token)) = (user, token) {
+                conn_opts
+                    .user_name(user) // Set the username
+                    .password(token); // Set the password
+                match v5 {
+                    true => conn_opts.clean_start(true),
+                    false => conn_opts.clean_session(true),
+                };
+            }
+            let conn_opts = conn_opts.finalize();
 
             if let Err(e) = cli.connect(conn_opts).await {
                 eprintln!("Unable to connect: {:?}", e);
@@ -259,42 +494,404 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let app = match &args.cmd {
         Commands::HTTP { .. } => AxumRouter::new()
-            .route("/*0", post(handler_http))
-            .with_state(state),
-        Commands::AMQP { .. } => AxumRouter::new()
-            .route("/*0", post(handler_amqp))
-            .with_state(state),
-        Commands::MQTT { .. } => AxumRouter::new()
-            .route("/*0", post(handler_mqtt))
-            .with_state(state),
+            .route("/loki/api/v1/query", get(handler_http).post(handler_http))
+            .route(
+                "/loki/api/v1/query_range",
+                get(handler_http).post(handler_http),
+            )
+            .route("/loki/api/v1/labels", get(handler_http).post(handler_http))
+            .route("/loki/api/v1/label/{name}/values", get(handler_http))
+            .route("/loki/api/v1/series", get(handler_http).post(handler_http))
+            .route("/loki/api/v1/tail", get(handler_tail))
+            .route("/{*0}", post(handler_http)),
+        Commands::AMQP { .. } => AxumRouter::new().route("/{*0}", post(handler_amqp)),
+        Commands::MQTT { .. } => AxumRouter::new().route("/{*0}", post(handler_mqtt)),
         #[cfg(feature = "iroh-support")]
-        Commands::IROH { .. } => AxumRouter::new()
-            .route("/{*0}", post(handler_iroh))
-            .with_state(state),
-    };
+        Commands::IROH { .. } => AxumRouter::new().route("/{*0}", post(handler_iroh)),
+    }
+    .layer(middleware::from_fn_with_state(
+        state.clone(),
+        auth::authenticate,
+    ))
+    // probes come without credentials
+    .route("/ready", get(handler_ready))
+    .layer(middleware::from_fn_with_state(state.clone(), limit_body))
+    .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:4000").await?;
-    info!("Loxxy listening on {}", listener.local_addr()?);
-    let _ = axum::serve(listener, app).await;
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let certs = ReloadingCert::load(cert.clone(), key.clone())?;
+            certs.watch(Duration::from_secs(args.tls_reload));
+            let clients = match &args.client_ca {
+                Some(ca) => Some(mtls::verifier(
+                    ca,
+                    matches!(args.auth, Authentication::Mtls),
+                )?),
+                None => None,
+            };
+            let config = listen::server_config(certs, clients)?;
+            Some(TlsAcceptor::from(Arc::new(config)))
+        }
+        _ => None,
+    };
+    let mut servers = JoinSet::new();
+    for listen in &args.listen {
+        let app = app
+            .clone()
+            .into_make_service_with_connect_info::<ClientCert>();
+        match (listen, &tls) {
+            (Listen::Tcp(addr), None) => {
+                let listener = TcpListener::bind(addr).await?;
+                servers.spawn(async move { axum::serve(listener, app).await });
+            }
+            (Listen::Tcp(addr), Some(tls)) => {
+                let listener = TlsListener::new(TcpListener::bind(addr).await?, tls.clone())?;
+                servers.spawn(async move { axum::serve(listener, app).await });
+            }
+            #[cfg(unix)]
+            (Listen::Unix(path), _) => {
+                // a socket left behind by a previous run would make bind fail
+                listen::remove_stale_socket(path)?;
+                let listener = tokio::net::UnixListener::bind(path)
+                    .with_context(|| format!("binding {}", listen))?;
+                servers.spawn(async move { axum::serve(listener, app).await });
+            }
+        }
+        info!(
+            "Loxxy listening on {}{}",
+            listen,
+            match (listen, &tls) {
+                (Listen::Tcp(_), Some(_)) => " with tls",
+                _ => "",
+            }
+        );
+    }
+    while let Some(served) = servers.join_next().await {
+        served??;
+    }
     Ok(())
 }
 
-async fn handler_http(
+/// Refuse bodies over `--max-body-bytes`, up front when their length is known and otherwise once
+/// reading them runs past it, which also bounds the bodies streamed on to Loki untouched
+async fn limit_body(
     State(state): State<Statey>,
-    mut req: Request,
+    req: Request,
+    next: middleware::Next,
 ) -> Result<Response, StatusCode> {
+    let limit = state.args.max_body_bytes;
+    let length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if length.is_some_and(|len| len > limit as u64) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let req = req.map(|body| Body::new(Limited::new(body, limit)));
+    Ok(next.run(req).await)
+}
+
+/// Read a whole request body, `413` when it is over `--max-body-bytes` and `400` when the client
+/// goes away before sending all of it
+async fn read_body(body: Body) -> Result<Bytes, StatusCode> {
+    match body.collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(e) => match e.into_inner().is::<LengthLimitError>() {
+            true => Err(StatusCode::PAYLOAD_TOO_LARGE),
+            false => Err(StatusCode::BAD_REQUEST),
+        },
+    }
+}
+
+/// Work out the tenant sent downstream, applying `--org-id-mode` to a client supplied one
+fn tenant(
+    state: &Statey,
+    identity: &Identity,
+    headers: &HeaderMap,
+) -> Result<Option<String>, StatusCode> {
+    let resolved = identity
+        .tenant
+        .clone()
+        .or(state.args.org_id.clone())
+        .or_else(|| state.loki_auth.org_id().map(str::to_string));
+    let supplied = headers
+        .get(ORG_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let trusted = matches!(state.args.auth, Authentication::None);
+    choose_tenant(resolved, supplied, &state.args.org_id_mode, trusted).inspect_err(|_| {
+        debug!("{} tried to act as another tenant", identity.user);
+    })
+}
+
+/// The tenant loxxy resolved wins, a client supplied one is only passed on without authentication
+/// or with `--org-id-mode trust`
+fn choose_tenant(
+    resolved: Option<String>,
+    supplied: Option<String>,
+    mode: &OrgIdMode,
+    trusted: bool,
+) -> Result<Option<String>, StatusCode> {
+    match (resolved, supplied, mode) {
+        (Some(resolved), Some(supplied), OrgIdMode::Reject) if resolved != supplied => {
+            Err(StatusCode::FORBIDDEN)
+        }
+        (Some(resolved), _, _) => Ok(Some(resolved)),
+        (None, supplied, _) if trusted => Ok(supplied),
+        (None, supplied, OrgIdMode::Trust) => Ok(supplied),
+        (None, Some(_), OrgIdMode::Reject) => Err(StatusCode::FORBIDDEN),
+        (None, _, _) => Ok(None),
+    }
+}
+
+/// The label policy an identity's pushes are held to
+fn policy<'a>(state: &'a Statey, identity: &'a Identity) -> &'a LabelPolicy {
+    identity.policy.as_ref().unwrap_or(&state.policy)
+}
+
+/// Apply the static and identity labels and the label policy to a push body, then convert it to
+/// `--output-format`
+fn rewrite(
+    state: &Statey,
+    identity: &Identity,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Payload, StatusCode> {
+    let labels: Vec<(String, String)> = state
+        .args
+        .labels
+        .iter()
+        .chain(&identity.labels)
+        .cloned()
+        .collect();
+    let policy = policy(state, identity);
+    push::rewrite(&body, headers, &labels, policy, state.args.output_format).map_err(|e| {
+        debug!("unable to rewrite push from {}: {:#}", identity.user, e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// Split a rewritten push by the key `template` renders for each of its streams
+///
+/// `{tenant}` is the tenant the push is stored under and `{user}` the identity pushing it, any
+/// other field is the stream's label of that name.
+fn route(
+    identity: &Identity,
+    tenant: Option<&str>,
+    template: &Template,
+    payload: Payload,
+    reserved: &[char],
+) -> Result<Vec<(String, Payload)>, StatusCode> {
+    if template.is_static() {
+        return Ok(vec![(template.render(|_| None, reserved), payload)]);
+    }
+    let key = |labels: &Labels| {
+        let lookup = |name: &str| match name {
+            "tenant" => tenant,
+            "user" => Some(identity.user.as_str()),
+            name => labels.get(name),
+        };
+        template.render(lookup, reserved)
+    };
+    push::split(payload, key).map_err(|e| {
+        debug!("unable to split push from {}: {:#}", identity.user, e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// Point a request at loki with loxxy's own credentials and the tenant it is allowed to act as
+///
+/// The client's credentials were for loxxy and are never passed on.
+fn upstream(
+    state: &Statey,
+    identity: &Identity,
+    loki_url: &str,
+    req: &mut Request,
+) -> Result<(), StatusCode> {
     let path = req.uri().path();
     let path_query = req
         .uri()
         .path_and_query()
         .map(|v| v.as_str())
         .unwrap_or(path);
+    let uri = format!("{}{}", loki_url, path_query);
+    debug!("uri:: {}", uri);
+
+    *req.uri_mut() = Uri::try_from(uri).map_err(|_| StatusCode::BAD_REQUEST)?;
+    // clients may speak h2 to loxxy, the pooled client only speaks http/1.1 to loki
+    *req.version_mut() = Version::HTTP_11;
+    state.loki_auth.authorize(req.headers_mut());
+    match tenant(state, identity, req.headers())? {
+        Some(tenant) => {
+            let org_id = HeaderValue::from_str(&tenant).map_err(|_| StatusCode::BAD_REQUEST)?;
+            req.headers_mut().insert(ORG_ID_HEADER, org_id);
+        }
+        None => {
+            req.headers_mut().remove(ORG_ID_HEADER);
+        }
+    }
+    Ok(())
+}
+
+/// Confine a read to the identity's labels by scoping the selectors in its query string
+///
+/// Identities without labels read unrestricted, anything that isn't a push or a known read is
+/// refused for the others.
+fn scope(identity: &Identity, req: &mut Request) -> Result<(), StatusCode> {
+    if identity.labels.is_empty() || push::is_push_path(req.uri().path()) {
+        return Ok(());
+    }
+    let param = logql::selector_param(req.uri().path()).ok_or_else(|| {
+        debug!(
+            "{} may only push and query, not {}",
+            identity.user,
+            req.uri()
+        );
+        StatusCode::FORBIDDEN
+    })?;
+    let params = logql::scope_params(req.uri().query().unwrap_or(""), param, &identity.labels)
+        .map_err(|e| {
+            debug!("refusing query from {}: {:#}", identity.user, e);
+            StatusCode::BAD_REQUEST
+        })?;
+    let path_query = format!("{}?{}", req.uri().path(), params);
+    *req.uri_mut() = Uri::try_from(path_query).map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(())
+}
+
+/// Readiness probe, answered by loki itself when proxying to it
+async fn handler_ready(State(state): State<Statey>) -> Result<Response, StatusCode> {
     match &state.args.cmd {
         Commands::HTTP { loki_uri: loki_url } => {
-            let uri = format!("{}{}", loki_url, path_query);
-            debug!("uri:: {}", uri);
+            let req = Request::builder()
+                .uri(format!("{}/ready", loki_url))
+                .body(Body::empty())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let resp = state
+                .client
+                .request(req)
+                .await
+                .map_err(|_| StatusCode::BAD_GATEWAY)?
+                .into_response();
+            Ok(resp)
+        }
+        _ => Ok("ready".into_response()),
+    }
+}
+
+/// Proxy the `tail` websocket by relaying the upgraded connections in both directions
+async fn handler_tail(
+    State(state): State<Statey>,
+    Extension(identity): Extension<Identity>,
+    mut req: Request,
+) -> Result<Response, StatusCode> {
+    let Commands::HTTP { loki_uri: loki_url } = &state.args.cmd else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let downstream = hyper::upgrade::on(&mut req);
+    scope(&identity, &mut req)?;
+    upstream(&state, &identity, loki_url, &mut req)?;
 
-            *req.uri_mut() = Uri::try_from(uri).unwrap();
+    let mut resp = state
+        .client
+        .request(req)
+        .await
+        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+    if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(resp.into_response());
+    }
+
+    let upstream = hyper::upgrade::on(&mut resp);
+    let user = identity.user.clone();
+    tokio::spawn(async move {
+        match tokio::try_join!(downstream, upstream) {
+            Ok((downstream, upstream)) => {
+                let mut downstream = TokioIo::new(downstream);
+                let mut upstream = TokioIo::new(upstream);
+                if let Err(e) = copy_bidirectional(&mut downstream, &mut upstream).await {
+                    debug!("tail for {} ended: {}", user, e);
+                }
+            }
+            Err(e) => debug!("unable to upgrade tail for {}: {}", user, e),
+        }
+    });
+    let (parts, _) = resp.into_parts();
+    Ok(Response::from_parts(parts, Body::empty()))
+}
+
+async fn handler_http(
+    State(state): State<Statey>,
+    Extension(identity): Extension<Identity>,
+    mut req: Request,
+) -> Result<Response, StatusCode> {
+    let is_push = push::is_push_path(req.uri().path());
+    let is_read = logql::selector_param(req.uri().path()).is_some();
+    let is_write = !matches!(*req.method(), Method::GET | Method::HEAD);
+    match &state.args.cmd {
+        Commands::HTTP { loki_uri: loki_url } => {
+            let param = logql::selector_param(req.uri().path()).unwrap_or("query");
+            scope(&identity, &mut req)?;
+            upstream(&state, &identity, loki_url, &mut req)?;
+            if !is_push && !identity.labels.is_empty() {
+                // reads may carry their query in a form body as well, which loki prefers over the
+                // url, so a body is either scoped too or refused
+                let (mut parts, body) = req.into_parts();
+                let bodydata = read_body(body).await?;
+                let is_form = parts
+                    .headers
+                    .get(CONTENT_TYPE)
+                    .and_then(|ct| ct.to_str().ok())
+                    .is_some_and(logql::is_form);
+                req = match (is_form, bodydata.is_empty()) {
+                    (true, _) => {
+                        let form =
+                            std::str::from_utf8(&bodydata).map_err(|_| StatusCode::BAD_REQUEST)?;
+                        let form =
+                            logql::scope_params(form, param, &identity.labels).map_err(|e| {
+                                debug!("refusing query from {}: {:#}", identity.user, e);
+                                StatusCode::BAD_REQUEST
+                            })?;
+                        parts.headers.remove(CONTENT_LENGTH);
+                        Request::from_parts(parts, Body::from(form))
+                    }
+                    (false, true) => Request::from_parts(parts, Body::empty()),
+                    (false, false) => {
+                        debug!("refusing read from {} with a non-form body", identity.user);
+                        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                    }
+                };
+            }
+            let unlabelled = state.args.labels.is_empty()
+                && identity.labels.is_empty()
+                && policy(&state, &identity).is_empty();
+            if is_write && !is_push && !is_read && !unlabelled {
+                // anything else posted may be logs loxxy can't label, such as otlp
+                debug!(
+                    "{} may only push to the loki push api, not {}",
+                    identity.user,
+                    req.uri().path()
+                );
+                return Err(StatusCode::FORBIDDEN);
+            }
+            let untouched = unlabelled && state.args.output_format == OutputFormat::Passthrough;
+            if is_push && !untouched {
+                let (mut parts, body) = req.into_parts();
+                let bodydata = read_body(body).await?;
+                let push = rewrite(&state, &identity, &parts.headers, bodydata)?;
+                parts.headers.remove(CONTENT_LENGTH);
+                parts.headers.remove(CONTENT_ENCODING);
+                parts.headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(push.format.content_type()),
+                );
+                if let Some(encoding) = &push.content_encoding {
+                    let encoding =
+                        HeaderValue::from_str(encoding).map_err(|_| StatusCode::BAD_REQUEST)?;
+                    parts.headers.insert(CONTENT_ENCODING, encoding);
+                }
+                req = Request::from_parts(parts, Body::from(push.body));
+            }
 
             let resp = state
                 .client
@@ -308,46 +905,102 @@ async fn handler_http(
     }
 }
 
-async fn handler_amqp(State(state): State<Statey>, body: Body) -> Result<Response, StatusCode> {
+async fn handler_amqp(
+    State(state): State<Statey>,
+    Extension(identity): Extension<Identity>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
     if let Commands::AMQP {
-        rmq_uri: _,
         exchange,
         queue,
-        routing_key: _,
+        routing_key,
+        ..
     } = &state.args.cmd
     {
-        let bodydata = body.collect().await.unwrap().to_bytes();
-        debug!("{:?}", bodydata);
-        let channel = state.amqp.unwrap();
-        channel
-            .basic_publish(
-                exchange,
-                queue,
-                BasicPublishOptions::default(),
-                &bodydata,
-                BasicProperties::default(),
-            )
-            .await
-            .expect("");
+        let bodydata = read_body(body).await?;
+        let push = rewrite(&state, &identity, &headers, bodydata)?;
+        let tenant = tenant(&state, &identity, &headers)?;
+        let routed = match routing_key {
+            Some(template) => route(&identity, tenant.as_deref(), template, push, AMQP_RESERVED)?,
+            None => vec![(queue.clone(), push)],
+        };
+        let channel = state.amqp.as_ref().unwrap();
+        for (routing_key, push) in routed {
+            debug!("{}: {:?}", routing_key, push.body);
+            let mut properties =
+                BasicProperties::default().with_content_type(push.format.content_type().into());
+            if let Some(encoding) = push.content_encoding {
+                properties = properties.with_content_encoding(encoding.into());
+            }
+            if let Some(tenant) = &tenant {
+                let mut amqp_headers = FieldTable::default();
+                amqp_headers.insert(
+                    ORG_ID_HEADER.into(),
+                    AMQPValue::LongString(tenant.as_str().into()),
+                );
+                properties = properties.with_headers(amqp_headers);
+            }
+            channel
+                .basic_publish(
+                    exchange,
+                    &routing_key,
+                    BasicPublishOptions::default(),
+                    &push.body,
+                    properties,
+                )
+                .await
+                .map_err(|e| {
+                    error!("publishing to {} failed: {}", exchange, e);
+                    StatusCode::BAD_GATEWAY
+                })?;
+        }
     }
 
     Ok(Default::default())
 }
 
-async fn handler_mqtt(State(state): State<Statey>, body: Body) -> Result<Response, StatusCode> {
+async fn handler_mqtt(
+    State(state): State<Statey>,
+    Extension(identity): Extension<Identity>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
     if let Commands::MQTT {
         mqtt_uri: _,
         user: _,
         token: _,
         topic,
         qos: _,
+        v5,
     } = &state.args.cmd
     {
-        let bodydata = body.collect().await.unwrap().to_bytes();
-        debug!("{:?}", bodydata);
+        let tenant = tenant(&state, &identity, &headers)?;
+        let mut properties = mqtt::Properties::new();
+        match (v5, &tenant) {
+            (true, Some(tenant)) => properties
+                .push_string_pair(mqtt::PropertyCode::UserProperty, ORG_ID_HEADER, tenant)
+                .map_err(|_| StatusCode::BAD_REQUEST)?,
+            (false, Some(tenant)) => debug!("not carrying tenant {} without --v5", tenant),
+            (_, None) => {}
+        }
+        let bodydata = read_body(body).await?;
+        let push = rewrite(&state, &identity, &headers, bodydata)?;
+        let routed = route(&identity, tenant.as_deref(), topic, push, MQTT_RESERVED)?;
         let cli = &state.mqtt.clone().unwrap();
-        let msg: Message = Message::new(topic, bodydata, 0);
-        let _ = cli.publish(msg).await;
+        for (topic, push) in routed {
+            debug!("{}: {:?}", topic, push.body);
+            let msg = mqtt::MessageBuilder::new()
+                .topic(topic)
+                .payload(push.body)
+                .qos(0)
+                .properties(properties.clone())
+                .finalize();
+            cli.publish(msg).await.map_err(|e| {
+                error!("publishing to mqtt failed: {}", e);
+                StatusCode::BAD_GATEWAY
+            })?;
+        }
     }
 
     Ok(Default::default())
@@ -355,75 +1008,97 @@ async fn handler_mqtt(State(state): State<Statey>, body: Body) -> Result<Respons
 #[axum::debug_handler]
 #[cfg(feature = "iroh-support")]
 
-async fn handler_iroh(State(state): State<Statey>, body: Body) -> Result<Response, StatusCode> {
+async fn handler_iroh(
+    State(state): State<Statey>,
+    Extension(identity): Extension<Identity>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, StatusCode> {
     if let Commands::IROH { node_id: _ } = &state.args.cmd {
-        let bodydata = body.collect().await.unwrap().to_bytes();
+        let bodydata = read_body(body).await?;
+        let push = rewrite(&state, &identity, &headers, bodydata)?;
+        let header = tunnel::Header {
+            tenant: tenant(&state, &identity, &headers)?,
+            content_type: Some(push.format.content_type().to_string()),
+            content_encoding: push.content_encoding,
+        };
+        let bodydata = push.body;
         debug!("Iroh handler received data: {:?}", bodydata);
 
         info!("Publishing to iroh");
 
-        match &state.iroh {
-            None => {
-                info!("no tunnel");
-            }
-            Some(iroh) => {
-                let conn = iroh.lock().await;
-
-                let (mut send, mut recv) = match conn.open_bi().await {
-                    Ok((send, recv)) => (send, recv),
-                    Err(e) => {
-                        info!("Failed to open bi-directional stream: {}", e);
-                        return Ok(Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::empty())
-                            .unwrap());
-                    }
-                };
-
-                let message = "ep is saying 'hello!'".to_string();
-
-                if let Err(e) = send.write_all(message.as_bytes()).await {
-                    info!("Failed to write to stream: {}", e);
-                    return Ok(Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Body::empty())
-                        .unwrap());
-                }
+        let Some(iroh) = &state.iroh else {
+            warn!("no tunnel to push through");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        };
+        let message =
+            tunnel::encode(&header, &bodydata).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        // the tunnel failing is loxxy's gateway being unavailable, ioxxy answers for loki
+        let unavailable = |what: &str, e: &dyn std::fmt::Display| {
+            warn!("{} iroh stream: {}", what, e);
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let conn = iroh.lock().await;
+        let (mut send, mut recv) = conn
+            .open_bi()
+            .await
+            .map_err(|e| unavailable("Failed to open", &e))?;
+        send.write_all(&message)
+            .await
+            .map_err(|e| unavailable("Failed to write to", &e))?;
+        send.finish()
+            .map_err(|e| unavailable("Failed to finish", &e))?;
+        let reply = recv
+            .read_to_end(2)
+            .await
+            .map_err(|e| unavailable("Failed to read from", &e))?;
+        let status =
+            tunnel::decode_status(&reply).map_err(|e| unavailable("Unexpected answer on", &e))?;
+        if status.is_server_error() {
+            warn!("ioxxy answered {} to a push", status);
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+        info!("Successfully sent data through iroh tunnel");
+        return Ok(status.into_response());
+    }
 
-                if let Err(e) = send.finish() {
-                    info!("Failed to finish stream: {}", e);
-                    return Ok(Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Body::empty())
-                        .unwrap());
-                }
+    Ok(Default::default())
+}
 
-                let message = match recv.read_to_end(100).await {
-                    Ok(data) => data,
-                    Err(e) => {
-                        info!("Failed to read from stream: {}", e);
-                        return Ok(Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::empty())
-                            .unwrap());
-                    }
-                };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                let message = match String::from_utf8(message) {
-                    Ok(msg) => msg,
-                    Err(e) => {
-                        info!("Failed to convert message to UTF-8: {}", e);
-                        return Ok(Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::empty())
-                            .unwrap());
-                    }
-                };
-                println!("received: {message}");
-                info!("Successfully sent data through iroh tunnel");
-            }
-        }
+    fn named(s: &str) -> Option<String> {
+        Some(s.to_string())
     }
 
-    Ok(Default::default())
+    #[test]
+    fn chooses_tenants() {
+        let (over, reject, trust) = (OrgIdMode::Override, OrgIdMode::Reject, OrgIdMode::Trust);
+        assert_eq!(
+            choose_tenant(named("a"), named("b"), &over, false),
+            Ok(named("a"))
+        );
+        assert_eq!(
+            choose_tenant(named("a"), named("b"), &reject, false),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            choose_tenant(named("a"), named("a"), &reject, false),
+            Ok(named("a"))
+        );
+        // an authenticated client can't pick a tenant loxxy didn't give it
+        assert_eq!(choose_tenant(None, named("b"), &over, false), Ok(None));
+        assert_eq!(
+            choose_tenant(None, named("b"), &reject, false),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            choose_tenant(None, named("b"), &trust, false),
+            Ok(named("b"))
+        );
+        assert_eq!(choose_tenant(None, named("b"), &over, true), Ok(named("b")));
+        assert_eq!(choose_tenant(None, None, &over, false), Ok(None));
+    }
 }
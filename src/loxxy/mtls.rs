@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Context};
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use clap_derive::ValueEnum;
+use rustls::pki_types::CertificateDer;
+use rustls::server::{danger::ClientCertVerifier, WebPkiClientVerifier};
+use rustls::RootCertStore;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use x509_parser::extensions::GeneralName;
+
+use crate::listen::TlsListener;
+
+/// Which part of a client certificate names the user
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CertName {
+    /// the subject's common name
+    Cn,
+    /// the first DNS, email or URI subject alternative name
+    San,
+}
+
+/// The certificate a client presented during the TLS handshake, kept as connect info
+#[derive(Debug, Clone)]
+pub struct ClientCert(pub Option<Arc<CertificateDer<'static>>>);
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientCert {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        let (_, session) = stream.io().get_ref();
+        let cert = session
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .map(|cert| Arc::new(cert.clone().into_owned()));
+        ClientCert(cert)
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientCert {
+    fn connect_info(_stream: IncomingStream<'_, TcpListener>) -> Self {
+        ClientCert(None)
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, tokio::net::UnixListener>> for ClientCert {
+    fn connect_info(_stream: IncomingStream<'_, tokio::net::UnixListener>) -> Self {
+        ClientCert(None)
+    }
+}
+
+/// Verify client certificates against the CA bundle at `ca`, only asking for them unless `required`
+pub fn verifier(ca: &Path, required: bool) -> Result<Arc<dyn ClientCertVerifier>, anyhow::Error> {
+    let raw = std::fs::read(ca).with_context(|| format!("reading {}", ca.display()))?;
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut raw.as_slice()) {
+        roots.add(cert?)?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("no certificates in {}", ca.display()));
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    let verifier = match required {
+        true => builder.build()?,
+        false => builder.allow_unauthenticated().build()?,
+    };
+    Ok(verifier)
+}
+
+/// The user name a verified client certificate maps to
+pub fn subject(cert: &CertificateDer, name: CertName) -> Result<String, anyhow::Error> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref())?;
+    let subject = match name {
+        CertName::Cn => cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .map(|cn| cn.as_str())
+            .transpose()?,
+        CertName::San => cert.subject_alternative_name()?.and_then(|san| {
+            san.value
+                .general_names
+                .iter()
+                .find_map(|general| match general {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(*name),
+                    _ => None,
+                })
+        }),
+    };
+    subject
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("certificate has no {:?} to name the user by", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // self-signed, CN=edge-01 with a DNS:edge-01.example.com subject alternative name
+    const NAMED: &str = "-----BEGIN CERTIFICATE-----
+MIIBmjCCAUGgAwIBAgIUR1S5p6cwqeyZarc52u206pHC9T0wCgYIKoZIzj0EAwIw
+EjEQMA4GA1UEAwwHZWRnZS0wMTAgFw0yNjEwMTgwNzAyNTdaGA8yMTI2MDkyNDA3
+MDI1N1owEjEQMA4GA1UEAwwHZWRnZS0wMTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABLD3/CPTJ/89hfjb+vCLzVX2tiyIS2wLB8jRdgEl7Tgep9lPnVZCcmT0j9xs
+4cKieuAgqKUxEbpiwKbWdVOiTpWjczBxMB0GA1UdDgQWBBQO387Ai6JhkoRL6MiI
+P/SD9FloLTAfBgNVHSMEGDAWgBQO387Ai6JhkoRL6MiIP/SD9FloLTAPBgNVHRMB
+Af8EBTADAQH/MB4GA1UdEQQXMBWCE2VkZ2UtMDEuZXhhbXBsZS5jb20wCgYIKoZI
+zj0EAwIDRwAwRAIgbFT4Pa+MYmAmseOp+Gdr5G7WMq8oGd5kKGcAOrTpKfcCIFTH
+ef6DoTxpb3zfAWWogo/q6M7gojev6Ez9TE8SV2hR
+-----END CERTIFICATE-----
+";
+
+    // self-signed, O=oxxy with neither a common name nor alternative names
+    const NAMELESS: &str = "-----BEGIN CERTIFICATE-----
+MIIBdDCCARugAwIBAgIUeBNcvHVBX6ExSk6cyaXBMTSDCMcwCgYIKoZIzj0EAwIw
+DzENMAsGA1UECgwEb3h4eTAgFw0yNjEwMTgwNzAyNTdaGA8yMTI2MDkyNDA3MDI1
+N1owDzENMAsGA1UECgwEb3h4eTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABBgl
+q6usR7D02Su5vQnyEsQglUmCFsrSGaN4/M05Lgp7D3mm4yK9vRrFiKitZpO7YtaH
+69mFFzKpa0USrYoAwPyjUzBRMB0GA1UdDgQWBBQMsjdmFnU3aXqV7tS1JiQEnv9b
+OzAfBgNVHSMEGDAWgBQMsjdmFnU3aXqV7tS1JiQEnv9bOzAPBgNVHRMBAf8EBTAD
+AQH/MAoGCCqGSM49BAMCA0cAMEQCIFNHNxknLeeNDhRrasePgwEAM8QkgJ2N93N+
+43sNQFeZAiB0T5TrjV75xcKRyMs7KVzqNSC+M9c+Lb8DL7xTO85yAA==
+-----END CERTIFICATE-----
+";
+
+    fn cert(pem: &str) -> CertificateDer<'static> {
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn names_users_by_certificate() {
+        let named = cert(NAMED);
+        assert_eq!(subject(&named, CertName::Cn).unwrap(), "edge-01");
+        assert_eq!(
+            subject(&named, CertName::San).unwrap(),
+            "edge-01.example.com"
+        );
+        let nameless = cert(NAMELESS);
+        assert!(subject(&nameless, CertName::Cn).is_err());
+        assert!(subject(&nameless, CertName::San).is_err());
+    }
+
+    #[test]
+    fn loads_client_ca_bundles() {
+        let dir = std::env::temp_dir();
+        let bundle = dir.join(format!("loxxy-{}-ca.pem", std::process::id()));
+        std::fs::write(&bundle, NAMED).unwrap();
+        assert!(verifier(&bundle, true).is_ok());
+        std::fs::write(&bundle, "not a certificate").unwrap();
+        assert!(verifier(&bundle, false).is_err());
+        std::fs::remove_file(&bundle).unwrap();
+        assert!(verifier(&bundle, true).is_err());
+    }
+}
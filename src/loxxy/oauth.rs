@@ -0,0 +1,328 @@
+use anyhow::{anyhow, Context};
+use axum::body::Body;
+use http::{Method, Request};
+use http_body_util::BodyExt;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use oxxy::shapes::Client;
+use serde_json::{Map, Value};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::auth::Identity;
+
+/// Token checks applied on top of the signature, built from loxxy's args
+#[derive(Debug, Clone, Default)]
+pub struct OauthPolicy {
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub tenant_claim: Option<String>,
+    /// `(claim, label)` pairs copied from the token into the pushed streams
+    pub claim_labels: Vec<(String, String)>,
+}
+
+// least time between two refreshes for tokens signed with a key we don't have, so they can't
+// hammer the identity provider
+const MIN_REFRESH: Duration = Duration::from_secs(30);
+
+/// A JSON Web Key Set loaded from a file or an http(s) url and refreshed in the background
+pub struct Jwks {
+    source: String,
+    client: Client,
+    keys: RwLock<JwkSet>,
+    /// when an unknown kid may next trigger a refresh
+    next_refresh: Mutex<Instant>,
+}
+
+pub type SharedJwks = Arc<Jwks>;
+
+impl Jwks {
+    pub async fn load(source: String, client: Client) -> Result<SharedJwks, anyhow::Error> {
+        let keys = fetch(&source, &client).await?;
+        info!("loaded {} keys from {}", keys.keys.len(), source);
+        Ok(Arc::new(Jwks {
+            source,
+            client,
+            keys: RwLock::new(keys),
+            next_refresh: Mutex::new(Instant::now() + MIN_REFRESH),
+        }))
+    }
+
+    pub fn refresh_every(self: &Arc<Self>, interval: Duration) {
+        let jwks = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                jwks.refresh().await;
+            }
+        });
+    }
+
+    async fn refresh(&self) {
+        match fetch(&self.source, &self.client).await {
+            Ok(keys) => {
+                debug!("refreshed {} keys from {}", keys.keys.len(), self.source);
+                *self.keys.write().unwrap() = keys;
+            }
+            Err(e) => warn!("keeping previous keys, refresh failed: {:#}", e),
+        }
+    }
+
+    fn find(&self, kid: Option<&str>) -> Option<Jwk> {
+        let keys = self.keys.read().unwrap();
+        match kid {
+            Some(kid) => keys.find(kid),
+            None if keys.keys.len() == 1 => keys.keys.first(),
+            None => None,
+        }
+        .cloned()
+    }
+
+    // whether an unknown kid may refresh the keys now, at most once every `MIN_REFRESH`
+    fn may_refresh(&self) -> bool {
+        let mut next = self.next_refresh.lock().unwrap();
+        let now = Instant::now();
+        if now < *next {
+            return false;
+        }
+        *next = now + MIN_REFRESH;
+        true
+    }
+
+    /// Verify a bearer token and map its claims onto an `Identity`
+    ///
+    /// A kid missing from the keys refreshes them first, in case the provider rotated its keys.
+    pub async fn validate(
+        &self,
+        token: &str,
+        policy: &OauthPolicy,
+    ) -> Result<Identity, anyhow::Error> {
+        let header = decode_header(token)?;
+        let kid = header.kid.as_deref();
+        let mut jwk = self.find(kid);
+        if jwk.is_none() && kid.is_some() && self.may_refresh() {
+            debug!("refreshing keys for unknown kid {:?}", header.kid);
+            self.refresh().await;
+            jwk = self.find(kid);
+        }
+        let jwk = jwk.ok_or_else(|| anyhow!("no key matching kid {:?}", header.kid))?;
+        let key = DecodingKey::from_jwk(&jwk)?;
+        // the algorithm comes from the key, the token only gets to agree with it
+        let alg = jwk
+            .common
+            .key_algorithm
+            .ok_or_else(|| anyhow!("key {:?} names no algorithm", header.kid))?;
+        let alg = Algorithm::from_str(&alg.to_string())
+            .map_err(|_| anyhow!("key {:?} is not for signing, but {alg}", header.kid))?;
+        if header.alg != alg {
+            return Err(anyhow!(
+                "token signed with {:?}, key is {alg:?}",
+                header.alg
+            ));
+        }
+
+        let mut validation = Validation::new(alg);
+        match &policy.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &policy.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let claims = decode::<Map<String, Value>>(token, &key, &validation)?.claims;
+
+        let user = claim(&claims, "sub").unwrap_or_else(|| "oauth".to_string());
+        let tenant = match &policy.tenant_claim {
+            Some(name) => Some(claim(&claims, name).ok_or_else(|| anyhow!("no {name} claim"))?),
+            None => None,
+        };
+        let labels = policy
+            .claim_labels
+            .iter()
+            .filter_map(|(name, label)| claim(&claims, name).map(|value| (label.clone(), value)))
+            .collect();
+        Ok(Identity {
+            user,
+            tenant,
+            labels,
+            policy: None,
+        })
+    }
+}
+
+// claims may be nested, `realm.team` looks up {"realm": {"team": ...}}
+fn claim(claims: &Map<String, Value>, path: &str) -> Option<String> {
+    let mut parts = path.split('.');
+    let mut value = claims.get(parts.next()?)?;
+    for part in parts {
+        value = value.get(part)?;
+    }
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+async fn fetch(source: &str, client: &Client) -> Result<JwkSet, anyhow::Error> {
+    let raw = if source.starts_with("http://") || source.starts_with("https://") {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(source)
+            .header("user-agent", "oxxy-loxxy")
+            .body(Body::empty())?;
+        let resp = client.request(req).await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("fetching {} returned {}", source, resp.status()));
+        }
+        resp.into_body().collect().await?.to_bytes().to_vec()
+    } else {
+        std::fs::read(source).with_context(|| format!("reading jwks {}", source))?
+    };
+    Ok(serde_json::from_slice(&raw)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing::get, Router};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use clap::Parser;
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use oxxy::shapes::ClientArgs;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        client: ClientArgs,
+    }
+
+    struct Key {
+        kid: &'static str,
+        pkcs8: Vec<u8>,
+        public: Vec<u8>,
+    }
+
+    impl Key {
+        fn generate(kid: &'static str) -> Self {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            Key {
+                kid,
+                pkcs8: pkcs8.as_ref().to_vec(),
+                public: pair.public_key().as_ref().to_vec(),
+            }
+        }
+
+        fn jwk(&self) -> Value {
+            json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "kid": self.kid,
+                "x": URL_SAFE_NO_PAD.encode(&self.public),
+            })
+        }
+
+        fn sign(&self, claims: Value) -> String {
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some(self.kid.to_string());
+            encode(&header, &claims, &EncodingKey::from_ed_der(&self.pkcs8)).unwrap()
+        }
+    }
+
+    fn claims(issuer: &str, audience: &str, expires_in: i64) -> Value {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        json!({
+            "sub": "edge-01",
+            "iss": issuer,
+            "aud": audience,
+            "exp": now + expires_in,
+            "org": "team-a",
+            "realm": {"team": "ops"},
+        })
+    }
+
+    // serves whatever keys are in `keys`, counting the fetches
+    async fn serve(keys: Arc<RwLock<Vec<Value>>>) -> String {
+        async fn jwks(State(keys): State<Arc<RwLock<Vec<Value>>>>) -> String {
+            json!({ "keys": *keys.read().unwrap() }).to_string()
+        }
+        let app = Router::new().route("/jwks", get(jwks)).with_state(keys);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}/jwks")
+    }
+
+    #[tokio::test]
+    async fn validates_tokens() {
+        let (a, b) = (Key::generate("a"), Key::generate("b"));
+        let keys = Arc::new(RwLock::new(vec![a.jwk()]));
+        let source = serve(keys.clone()).await;
+        let client = Args::parse_from(["test"]).client.client().unwrap();
+        let jwks = Jwks::load(source, client).await.unwrap();
+        let policy = OauthPolicy {
+            issuer: Some("https://idp".to_string()),
+            audience: Some("loxxy".to_string()),
+            tenant_claim: Some("org".to_string()),
+            claim_labels: vec![("realm.team".to_string(), "team".to_string())],
+        };
+
+        let token = a.sign(claims("https://idp", "loxxy", 600));
+        let identity = jwks.validate(&token, &policy).await.unwrap();
+        assert_eq!(identity.user, "edge-01");
+        assert_eq!(identity.tenant.as_deref(), Some("team-a"));
+        assert_eq!(
+            identity.labels,
+            vec![("team".to_string(), "ops".to_string())]
+        );
+
+        for claims in [
+            claims("https://idp", "loxxy", -600),
+            claims("https://other", "loxxy", 600),
+            claims("https://idp", "other", 600),
+        ] {
+            assert!(jwks.validate(&a.sign(claims), &policy).await.is_err());
+        }
+
+        // a rotated key is picked up, but only once the last refresh is old enough
+        keys.write().unwrap().push(b.jwk());
+        let token = b.sign(claims("https://idp", "loxxy", 600));
+        assert!(jwks.validate(&token, &policy).await.is_err());
+        *jwks.next_refresh.lock().unwrap() = Instant::now();
+        assert!(jwks.validate(&token, &policy).await.is_ok());
+        assert!(!jwks.may_refresh());
+
+        let unknown = Key::generate("c").sign(claims("https://idp", "loxxy", 600));
+        assert!(jwks.validate(&unknown, &policy).await.is_err());
+
+        // the key decides the algorithm, not the token
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("a".to_string());
+        let secret = EncodingKey::from_secret(&a.public);
+        let forged = encode(&header, &claims("https://idp", "loxxy", 600), &secret).unwrap();
+        assert!(jwks.validate(&forged, &policy).await.is_err());
+
+        let d = Key::generate("d");
+        let mut jwk = d.jwk();
+        jwk.as_object_mut().unwrap().remove("alg");
+        keys.write().unwrap().push(jwk);
+        *jwks.next_refresh.lock().unwrap() = Instant::now();
+        let token = d.sign(claims("https://idp", "loxxy", 600));
+        assert!(jwks.validate(&token, &policy).await.is_err());
+        assert!(jwks.find(Some("d")).is_some());
+    }
+}
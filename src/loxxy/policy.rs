@@ -0,0 +1,105 @@
+use anyhow::anyhow;
+use clap_derive::ValueEnum;
+use oxxy::shapes::Labels;
+use serde::Deserialize;
+
+/// What to do with a push carrying a label its sender may not set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Forbidden {
+    /// drop the label and keep the stream
+    #[default]
+    Strip,
+    /// refuse the whole push
+    Reject,
+}
+
+/// Label rules applied to every pushed stream, from the users file or loxxy's args
+///
+/// ```toml
+/// [users.edge-01.policy]
+/// forbidden = ["hostname", "tenant"]
+/// on_forbidden = "reject"
+/// max_labels = 8
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LabelPolicy {
+    /// labels the client may not send itself, forced labels are still set
+    #[serde(default)]
+    pub forbidden: Vec<String>,
+    #[serde(default)]
+    pub on_forbidden: Forbidden,
+    /// most labels a stream may carry once the forced ones are set
+    pub max_labels: Option<usize>,
+}
+
+impl LabelPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.forbidden.is_empty() && self.max_labels.is_none()
+    }
+
+    /// Apply the policy to the labels a client sent, setting `forced` over them
+    pub fn apply(
+        &self,
+        labels: &mut Labels,
+        forced: &[(String, String)],
+    ) -> Result<(), anyhow::Error> {
+        match self.on_forbidden {
+            Forbidden::Strip => labels.retain(|name, _| !self.forbidden.iter().any(|f| f == name)),
+            Forbidden::Reject => {
+                if let Some(name) = self.forbidden.iter().find(|f| labels.get(f).is_some()) {
+                    return Err(anyhow!("label {name} may not be set"));
+                }
+            }
+        }
+        labels.extend(forced.iter().cloned());
+        match self.max_labels {
+            Some(max) if labels.len() > max => Err(anyhow!(
+                "stream has {} labels, at most {max} are allowed",
+                labels.len()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent() -> Labels {
+        [("app", "web"), ("hostname", "spoofed")]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn forbidden_labels() {
+        let forced = vec![("team".to_string(), "a".to_string())];
+        let mut policy = LabelPolicy {
+            forbidden: vec!["hostname".to_string()],
+            ..Default::default()
+        };
+        let mut labels = sent();
+        policy.apply(&mut labels, &forced).unwrap();
+        assert_eq!(labels.to_string(), r#"{app="web", team="a"}"#);
+
+        policy.on_forbidden = Forbidden::Reject;
+        assert!(policy.apply(&mut sent(), &forced).is_err());
+    }
+
+    #[test]
+    fn label_cap() {
+        let forced = vec![("hostname".to_string(), "edge-01".to_string())];
+        let mut policy = LabelPolicy {
+            max_labels: Some(2),
+            ..Default::default()
+        };
+        let mut labels = sent();
+        policy.apply(&mut labels, &forced).unwrap();
+        assert_eq!(labels.get("hostname"), Some("edge-01"));
+
+        policy.max_labels = Some(1);
+        assert!(policy.apply(&mut sent(), &forced).is_err());
+    }
+}
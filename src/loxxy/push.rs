@@ -0,0 +1,176 @@
+use http::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use http::HeaderMap;
+use oxxy::shapes::{Format, Labels, OutputFormat, Payload, PushRequest, Stream};
+use std::collections::BTreeMap;
+
+use crate::policy::LabelPolicy;
+
+/// Whether `path` is a Loki push endpoint loxxy can decode and rewrite
+///
+/// Other ingest endpoints, such as `/otlp/v1/logs`, would carry streams past the labels and policy.
+pub fn is_push_path(path: &str) -> bool {
+    matches!(path, "/loki/api/v1/push" | "/api/prom/push")
+}
+
+/// Set `labels` on every stream, replacing any the client sent, and re-encode the push as `output`
+///
+/// The body is passed along untouched when there are no labels to set, no policy to check and no
+/// conversion to do.
+pub fn rewrite(
+    body: &[u8],
+    headers: &HeaderMap,
+    labels: &[(String, String)],
+    policy: &LabelPolicy,
+    output: OutputFormat,
+) -> Result<Payload, anyhow::Error> {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let content_encoding = headers.get(CONTENT_ENCODING).and_then(|v| v.to_str().ok());
+    let received = Payload {
+        body: body.to_vec(),
+        format: Format::from_content_type(content_type),
+        content_encoding: content_encoding.map(str::to_string),
+    };
+    if labels.is_empty() && policy.is_empty() && output == OutputFormat::Passthrough {
+        return Ok(received);
+    }
+
+    let (mut push, format) = PushRequest::decode_http(body, content_type, content_encoding)?;
+    for stream in &mut push.streams {
+        policy.apply(&mut stream.labels, labels)?;
+    }
+    match output {
+        // the labels changed, so re-encode in the format the push came in
+        OutputFormat::Passthrough => Ok(Payload {
+            body: push.encode(format)?,
+            format,
+            content_encoding: None,
+        }),
+        output => output.convert(&push, received),
+    }
+}
+
+/// Split a push into one payload per key its streams render to
+///
+/// A push whose streams all render to the same key is passed along untouched.
+pub fn split(
+    payload: Payload,
+    key: impl Fn(&Labels) -> String,
+) -> Result<Vec<(String, Payload)>, anyhow::Error> {
+    let (push, format) = PushRequest::decode_http(
+        &payload.body,
+        Some(payload.format.content_type()),
+        payload.content_encoding.as_deref(),
+    )?;
+    let mut routed: BTreeMap<String, Vec<Stream>> = BTreeMap::new();
+    for stream in push.streams {
+        routed.entry(key(&stream.labels)).or_default().push(stream);
+    }
+    if routed.len() == 1 {
+        let key = routed.into_keys().next().unwrap_or_default();
+        return Ok(vec![(key, payload)]);
+    }
+    routed
+        .into_iter()
+        .map(|(key, streams)| {
+            let payload = Payload {
+                body: PushRequest { streams }.encode(format)?,
+                format,
+                content_encoding: None,
+            };
+            Ok((key, payload))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+    use oxxy::shapes::{Entry, Timestamp};
+
+    fn push(apps: &[&str]) -> PushRequest {
+        PushRequest {
+            streams: apps
+                .iter()
+                .enumerate()
+                .map(|(i, app)| Stream {
+                    labels: [("app", *app)].into_iter().collect(),
+                    values: vec![Entry::new(Timestamp::from_nanos(i as i64 + 1), "line")],
+                })
+                .collect(),
+        }
+    }
+
+    fn app(labels: &Labels) -> String {
+        labels.get("app").unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn splits_by_key() {
+        let payload = Payload {
+            body: push(&["web", "db", "web"])
+                .encode(Format::Protobuf)
+                .unwrap(),
+            format: Format::Protobuf,
+            content_encoding: None,
+        };
+        let routed = split(payload, app).unwrap();
+        let keys: Vec<_> = routed.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["db", "web"]);
+        for (key, payload) in routed {
+            assert_eq!(
+                (payload.format, payload.content_encoding),
+                (Format::Protobuf, None)
+            );
+            let push = PushRequest::decode(&payload.body, payload.format).unwrap();
+            assert!(push.streams.iter().all(|stream| app(&stream.labels) == key));
+            assert_eq!(push.entries(), if key == "web" { 2 } else { 1 });
+        }
+
+        // one key needs no splitting, so the body isn't even re-encoded
+        let mut body = push(&["web", "web"]).encode(Format::Json).unwrap();
+        body.push(b'\n');
+        let payload = Payload {
+            body,
+            format: Format::Json,
+            content_encoding: None,
+        };
+        assert_eq!(
+            split(payload.clone(), app).unwrap(),
+            vec![("web".to_string(), payload)]
+        );
+    }
+
+    #[test]
+    fn rewrites_gzip_bodies() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(&push(&["web", "db"]).encode(Format::Json).unwrap())
+            .unwrap();
+        let body = gz.finish().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let policy = LabelPolicy::default();
+
+        let untouched = rewrite(&body, &headers, &[], &policy, OutputFormat::Passthrough).unwrap();
+        assert_eq!(untouched.body, body);
+        assert_eq!(untouched.content_encoding.as_deref(), Some("gzip"));
+
+        let labels = [("env".to_string(), "prod".to_string())];
+        let payload =
+            rewrite(&body, &headers, &labels, &policy, OutputFormat::Passthrough).unwrap();
+        assert_eq!(
+            (payload.format, payload.content_encoding),
+            (Format::Json, None)
+        );
+        let push = PushRequest::decode(&payload.body, payload.format).unwrap();
+        assert_eq!(push.streams.len(), 2);
+        assert!(push
+            .streams
+            .iter()
+            .all(|stream| stream.labels.get("env") == Some("prod")));
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::anyhow;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString};
+use argon2::{Argon2, PasswordVerifier};
+use lapin::uri::AMQPUri;
+use lapin::{Connection as LapinConnection, ConnectionProperties};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+// users remembered at once, by successful and by failed logins each
+const MAX_CACHED: usize = 1024;
+
+// how long a failed login keeps further attempts for the user from reaching the broker
+const FAILURE_TTL: Duration = Duration::from_secs(5);
+
+/// Authenticates clients by logging into RabbitMQ with their basic auth credentials
+///
+/// Successful logins are remembered for `ttl` as an argon2 hash, so that not every push opens a
+/// broker connection. After a failed login the user is refused for a few seconds without asking the
+/// broker, so guessing passwords through loxxy is no faster than against RabbitMQ itself.
+pub struct RabbitAuth {
+    uri: AMQPUri,
+    ttl: Duration,
+    logins: Mutex<HashMap<String, (String, Instant)>>,
+    failures: Mutex<HashMap<String, Instant>>,
+}
+
+impl RabbitAuth {
+    pub fn new(uri: &str, ttl: Duration) -> Result<Self, anyhow::Error> {
+        let uri = AMQPUri::from_str(uri).map_err(|e| anyhow!("invalid rabbit uri: {e}"))?;
+        Ok(RabbitAuth {
+            uri,
+            ttl,
+            logins: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn cached(&self, user: &str) -> Option<String> {
+        let mut logins = self.logins.lock().unwrap();
+        match logins.get(user) {
+            Some((hash, at)) if at.elapsed() < self.ttl => Some(hash.clone()),
+            Some(_) => {
+                logins.remove(user);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn cooling_off(&self, user: &str) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get(user) {
+            Some(at) if at.elapsed() < FAILURE_TTL => true,
+            Some(_) => {
+                failures.remove(user);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub async fn login(&self, user: &str, password: &str) -> bool {
+        if let Some(hash) = self.cached(user) {
+            let password = password.to_string();
+            let verified = tokio::task::spawn_blocking(move || verify(&hash, &password))
+                .await
+                .unwrap_or(false);
+            if verified {
+                return true;
+            }
+        }
+        if self.cooling_off(user) {
+            debug!("refusing {} after a recent failed rabbit login", user);
+            return false;
+        }
+
+        let mut uri = self.uri.clone();
+        uri.authority.userinfo.username = user.to_string();
+        uri.authority.userinfo.password = password.to_string();
+        match LapinConnection::connect_uri(uri, ConnectionProperties::default()).await {
+            Ok(connection) => {
+                let _ = connection.close(200, "loxxy auth check").await;
+                let password = password.to_string();
+                if let Ok(Some(hash)) = tokio::task::spawn_blocking(move || hash(&password)).await {
+                    let mut logins = self.logins.lock().unwrap();
+                    remember(&mut logins, user, (hash, Instant::now()), self.ttl, |v| v.1);
+                }
+                true
+            }
+            Err(e) => {
+                debug!("rabbit login for {} failed: {}", user, e);
+                let mut failures = self.failures.lock().unwrap();
+                remember(&mut failures, user, Instant::now(), FAILURE_TTL, |at| *at);
+                false
+            }
+        }
+    }
+}
+
+fn hash(password: &str) -> Option<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .ok()
+        .map(|hash| hash.to_string())
+}
+
+fn verify(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+// expired entries go first and then the oldest, so the cache stays under `MAX_CACHED`
+fn remember<V>(
+    cache: &mut HashMap<String, V>,
+    user: &str,
+    value: V,
+    ttl: Duration,
+    at: fn(&V) -> Instant,
+) {
+    if cache.len() >= MAX_CACHED && !cache.contains_key(user) {
+        cache.retain(|_, v| at(v).elapsed() < ttl);
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, v)| at(v))
+            .map(|(user, _)| user.clone());
+        if let (true, Some(oldest)) = (cache.len() >= MAX_CACHED, oldest) {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(user.to_string(), value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_hashes_within_bounds() {
+        let hash = hash("s3cret").unwrap();
+        assert!(!hash.contains("s3cret"));
+        assert!(verify(&hash, "s3cret"));
+        assert!(!verify(&hash, "guess"));
+
+        let mut cache = HashMap::new();
+        let (ttl, start) = (Duration::from_secs(60), Instant::now());
+        for i in 0..MAX_CACHED + 10 {
+            let at = start + Duration::from_millis(i as u64);
+            remember(&mut cache, &format!("user-{i}"), at, ttl, |at| *at);
+        }
+        assert_eq!(cache.len(), MAX_CACHED);
+        assert!(!cache.contains_key("user-0"));
+        assert!(cache.contains_key(&format!("user-{}", MAX_CACHED + 9)));
+    }
+}
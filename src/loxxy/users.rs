@@ -0,0 +1,174 @@
+use anyhow::Context;
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+use tracing::{info, warn};
+
+use crate::policy::LabelPolicy;
+
+/// A single entry of the users file
+///
+/// ```toml
+/// [users.alice]
+/// password = "$2b$12$..." # bcrypt or argon2 (PHC string) hash
+/// tenant = "team-a"       # Loki org ID pushed as X-Scope-OrgID
+/// labels = { team = "a" } # set on every push, and required on every query
+///
+/// [users.alice.policy]    # see `LabelPolicy`, defaults to loxxy's args
+/// forbidden = ["hostname"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    password: String,
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub policy: Option<LabelPolicy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Users {
+    #[serde(default)]
+    users: HashMap<String, User>,
+}
+
+pub type SharedUsers = Arc<RwLock<Users>>;
+
+impl Users {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("reading users file {}", path.display()))?;
+        let users: Users = toml::from_str(&raw)
+            .with_context(|| format!("parsing users file {}", path.display()))?;
+        info!("loaded {} users from {}", users.users.len(), path.display());
+        Ok(users)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+}
+
+impl User {
+    /// Check a plaintext password against the stored hash, this is deliberately slow
+    pub fn verify(&self, password: &str) -> bool {
+        if self.password.starts_with("$argon2") {
+            match PasswordHash::new(&self.password) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(e) => {
+                    warn!("unparseable argon2 hash: {}", e);
+                    false
+                }
+            }
+        } else if self.password.starts_with("$2") {
+            bcrypt::verify(password, &self.password).unwrap_or(false)
+        } else {
+            warn!("unsupported password hash, expected bcrypt or argon2");
+            false
+        }
+    }
+}
+
+// stands in for users that don't exist, so they take as long to refuse as a wrong password
+static DUMMY: LazyLock<User> = LazyLock::new(|| User {
+    password: bcrypt::hash("loxxy", bcrypt::DEFAULT_COST).unwrap_or_default(),
+    tenant: None,
+    labels: BTreeMap::new(),
+    policy: None,
+});
+
+/// Check a password for a user that may not exist, an unknown user is refused after the same work
+pub fn verify(user: Option<&User>, password: &str) -> bool {
+    match user {
+        Some(user) => user.verify(password),
+        None => {
+            DUMMY.verify(password);
+            false
+        }
+    }
+}
+
+/// Replace `users` with the users file at `path`, keeping the old set on errors
+pub fn reload(path: &Path, users: &SharedUsers) {
+    match Users::load(path) {
+        Ok(loaded) => *users.write().unwrap() = loaded,
+        Err(e) => warn!("keeping previous users, reload failed: {:#}", e),
+    }
+}
+
+/// Reload the users file whenever loxxy receives a SIGHUP
+#[cfg(unix)]
+pub fn reload_on_sighup(path: PathBuf, users: SharedUsers) -> Result<(), anyhow::Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            reload(&path, &users);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+
+    fn users_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("loxxy-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn verifies_bcrypt_and_argon2() {
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2 = Argon2::default()
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let path = users_file(
+            "verify",
+            &format!(
+                "[users.alice]\npassword = \"{bcrypt}\"\ntenant = \"team-a\"\n\
+                 [users.bob]\npassword = \"{argon2}\"\nlabels = {{ team = \"b\" }}\n\
+                 [users.eve]\npassword = \"hunter2\"\n"
+            ),
+        );
+        let users = Users::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let alice = users.get("alice").unwrap();
+        assert_eq!(alice.tenant.as_deref(), Some("team-a"));
+        assert!(alice.verify("hunter2") && !alice.verify("hunter3"));
+        let bob = users.get("bob").unwrap();
+        assert_eq!(bob.labels.get("team").map(String::as_str), Some("b"));
+        assert!(bob.verify("hunter2") && !bob.verify("hunter3"));
+        // plaintext passwords are never accepted
+        assert!(!users.get("eve").unwrap().verify("hunter2"));
+        assert!(!verify(users.get("mallory"), "hunter2"));
+    }
+
+    #[test]
+    fn bad_reload_keeps_previous_users() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        let path = users_file("reload", &format!("[users.alice]\npassword = \"{hash}\"\n"));
+        let users: SharedUsers = Arc::new(RwLock::new(Users::load(&path).unwrap()));
+
+        std::fs::write(&path, "[users.alice\n").unwrap();
+        reload(&path, &users);
+        assert!(users.read().unwrap().get("alice").is_some());
+
+        std::fs::write(&path, format!("[users.bob]\npassword = \"{hash}\"\n")).unwrap();
+        reload(&path, &users);
+        std::fs::remove_file(&path).unwrap();
+        let users = users.read().unwrap();
+        assert!(users.get("alice").is_none() && users.get("bob").is_some());
+    }
+}
@@ -1,15 +1,17 @@
-use axum::body::Body;
+use axum::body::{Body, Bytes};
 use axum::extract::Request;
-use axum::response::IntoResponse;
 
 use clap::Parser;
 
-use http::{Method, StatusCode};
-use hyper_util::client::legacy::connect::HttpConnector;
-use hyper_util::rt::TokioExecutor;
+use http::Method;
 
-use log::{debug, error, info};
-use oxxy::shapes::{Client, LogMessage};
+use log::{debug, error, info, warn};
+use oxxy::batch::{BatchArgs, Batcher};
+use oxxy::retry::{Outcome, RetryArgs};
+use oxxy::shapes::{
+    sniff_encoding, ClientArgs, LokiAuthArgs, OutputFormat, Payload, PushRequest, ORG_ID_HEADER,
+};
+use oxxy::template::Template;
 use paho_mqtt as mqtt;
 
 use std::sync::Arc;
@@ -32,10 +34,30 @@ struct Args {
     user: Option<String>,
     #[arg(short, long)]
     token: Option<String>,
-    #[arg(long, default_value = "#/#/#/logs")]
-    topic: String,
+    /// Topic filter to subscribe to, derived from `--topic-template` when unset, `#` without either
+    #[arg(long)]
+    topic: Option<String>,
+    /// Topic loxxy publishes to, such as `{tenant}/{hostname}/{app}/logs`, read back into the tenant
+    /// and labels missing from the message
+    #[arg(long)]
+    topic_template: Option<Template>,
     #[arg(short, long, default_value = "0")]
     qos: i32,
+    /// Re-encode pushes before forwarding them to loki
+    #[arg(long, value_enum, default_value = "passthrough")]
+    output_format: OutputFormat,
+
+    #[command(flatten)]
+    client: ClientArgs,
+    #[command(flatten)]
+    loki_auth: LokiAuthArgs,
+    #[command(flatten)]
+    batch: BatchArgs,
+    #[command(flatten)]
+    retry: RetryArgs,
+    /// Connect with MQTT v5 and forward the tenant loxxy attached as a user property
+    #[arg(long)]
+    v5: bool,
 }
 
 #[tokio::main]
@@ -43,22 +65,32 @@ async fn main() -> Result<(), anyhow::Error> {
     let args = Args::parse();
     env_logger::init();
 
+    let client = args.client.client()?;
+    let loki_auth = args.loki_auth.auth()?;
     let create_opts = mqtt::CreateOptionsBuilder::new()
         .server_uri(&args.mqtt_uri)
         .client_id("oxxy-moxxy") // Set a client ID for your connection
+        .mqtt_version(match args.v5 {
+            true => mqtt::MQTT_VERSION_5,
+            false => mqtt::MQTT_VERSION_DEFAULT,
+        })
         .finalize();
     let cli = mqtt::AsyncClient::new(create_opts)?;
 
-    let conn_opts = match (&args.user, &args.token) {
-        (Some(user), Some(token)) => {
-            mqtt::ConnectOptionsBuilder::new()
-                .user_name(user) // Set the username
-                .password(token) // Set the password
-                .clean_session(true) // Set clean session
-                .finalize()
-        }
-        _ => mqtt::ConnectOptionsBuilder::new().finalize(),
+    let mut conn_opts = match args.v5 {
+        true => mqtt::ConnectOptionsBuilder::new_v5(),
+        false => mqtt::ConnectOptionsBuilder::new(),
     };
+    if let (Some(user), Some(token)) = (&args.user, &args.token) {
+        conn_opts
+            .user_name(user) // Set the username
+            .password(token); // Set the password
+        match args.v5 {
+            true => conn_opts.clean_start(true),
+            false => conn_opts.clean_session(true),
+        };
+    }
+    let conn_opts = conn_opts.finalize();
 
     if let Err(e) = cli.connect(conn_opts).await {
         error!("Unable to connect: {:?}", e);
@@ -67,11 +99,16 @@ async fn main() -> Result<(), anyhow::Error> {
 
     info!("Moxxy Connected");
 
-    if let Err(e) = cli.subscribe(&args.topic, args.qos).await {
+    let topic = match (&args.topic, &args.topic_template) {
+        (Some(topic), _) => topic.clone(),
+        (None, Some(template)) => template.filter('/', "+"),
+        (None, None) => "#".to_string(),
+    };
+    if let Err(e) = cli.subscribe(&topic, args.qos).await {
         error!("Error subscribing to topic: {:?}", e);
         return Ok(());
     }
-    info!("Subscribing to topic {:?}", args.topic);
+    info!("Subscribing to topic {:?}", topic);
 
     let (tx, mut rx) = mpsc::channel(100);
     let tx = Arc::new(tx); // Arc for sharing the sender across threads
@@ -81,53 +118,114 @@ async fn main() -> Result<(), anyhow::Error> {
     cli.set_message_callback(move |_cli, msg| {
         let tx = Arc::clone(&tx);
         if let Some(msg) = msg {
-            // let topic = msg.topic().to_string();
+            let topic = msg.topic().to_string();
             let payload = msg.payload().to_vec();
+            let tenant = msg.properties().find_user_property(ORG_ID_HEADER);
             // Spawn a new async task to send the message to the channel
             rt_handle.spawn(async move {
-                if let Err(e) = tx.send(payload).await {
-                    eprintln!("Error sending message: {:?}", e);
+                if let Err(e) = tx.send((payload, tenant, topic)).await {
+                    error!("Error queueing message from {}: {}", e.0 .2, e);
                 }
             });
             // debug!("{} - {:?}", topic, payload);
         }
     });
-    tokio::spawn(async move {
-        while let Some(payload) = rx.recv().await {
-            println!("Received message:");
-            println!("Message: {:?}", &payload);
-            let contenttype = match serde_json::from_slice::<LogMessage>(&payload) {
-                Ok(log_message) => {
-                    debug!("Successfully deserialized log message: {:?}", log_message);
-                    "application/json"
-                }
+    let topic_template = args.topic_template.clone();
+    let loki_uri = Arc::new(args.loki_uri.clone());
+    let retry = Arc::new(args.retry.clone());
+    let batcher = Batcher::spawn(args.batch.clone(), args.output_format, move |batch| {
+        let (client, loki_auth, loki_uri) = (client.clone(), loki_auth.clone(), loki_uri.clone());
+        let retry = retry.clone();
+        async move {
+            let payload = match batch.payload {
+                Ok(payload) => payload,
                 Err(e) => {
-                    debug!("Failed to deserialize log message as json: {}", e);
-                    "application/x-protobuf"
+                    error!("Dropping batch of {} entries: {:#}", batch.entries, e);
+                    return;
                 }
             };
-            let payload_ = payload.to_vec();
-            // Perform async operations here if needed
-            // e.g., save to a database, make an HTTP request, etc.
-            let client: Client =
-                hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
-                    .build(HttpConnector::new());
-            let req = Request::builder()
-                .header("content-type", contenttype)
-                .header("user-agent", "oxxy-moxxy")
-                .method(Method::POST)
-                .uri(args.loki_uri.as_str())
-                .body(Body::from(payload_))
-                .expect("Request builder error");
-            let _resp = client
-                .request(req)
-                .await
-                .map_err(|_| StatusCode::BAD_REQUEST)
-                .expect("bad req")
-                .into_response();
+            let body = Bytes::from(payload.body);
+            let outcome = retry
+                .send(&client, || {
+                    let mut req = Request::builder()
+                        .header("content-type", payload.format.content_type())
+                        .header("user-agent", "oxxy-moxxy")
+                        .method(Method::POST)
+                        .uri(loki_uri.as_str());
+                    if let Some(encoding) = &payload.content_encoding {
+                        req = req.header("content-encoding", encoding);
+                    }
+                    Ok(loki_auth
+                        .apply(req, batch.tenant.as_deref())
+                        .body(Body::from(body.clone()))?)
+                })
+                .await;
+            match outcome {
+                Outcome::Accepted => debug!("Pushed {} entries", batch.entries),
+                outcome => error!("Dropping push of {} entries, {}", batch.entries, outcome),
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some((payload, tenant, topic)) = rx.recv().await {
+            match PushRequest::sniff(&payload) {
+                Ok((mut push, format)) => {
+                    debug!("Successfully decoded {:?} push: {:?}", format, push);
+                    let mut received = Payload {
+                        content_encoding: sniff_encoding(&payload).map(str::to_string),
+                        body: payload,
+                        format,
+                    };
+                    let mut tenant = tenant;
+                    if let Some(template) = &topic_template {
+                        let fields = template.parse(&topic).unwrap_or_else(|| {
+                            warn!("Topic {} does not fit {}", topic, template);
+                            vec![]
+                        });
+                        let (tenants, labels): (Vec<_>, Vec<_>) =
+                            fields.into_iter().partition(|(name, _)| name == "tenant");
+                        tenant = tenant.or(tenants.into_iter().next().map(|(_, value)| value));
+                        if fill_labels(&mut push, &labels) {
+                            // the labels changed, so the message can't be passed along as is
+                            match push.encode(format) {
+                                Ok(body) => {
+                                    received = Payload {
+                                        body,
+                                        format,
+                                        content_encoding: None,
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("Unable to re-encode push: {}", e);
+                                    continue;
+                                }
+                            }
+                        }
+                    }
+                    if let Err(e) = batcher.add(tenant, push, received, ()).await {
+                        error!("Unable to queue push: {}", e);
+                    }
+                }
+                Err(e) => error!("Dropping message that is not a loki push: {}", e),
+            }
         }
     });
     loop {
         tokio::time::sleep(Duration::from_millis(100)).await;
     }
 }
+
+/// Set the labels read from a topic on every stream that doesn't carry them already
+fn fill_labels(push: &mut PushRequest, labels: &[(String, String)]) -> bool {
+    let mut changed = false;
+    for stream in &mut push.streams {
+        let missing: Vec<_> = labels
+            .iter()
+            .filter(|(name, _)| stream.labels.get(name).is_none())
+            .cloned()
+            .collect();
+        changed |= !missing.is_empty();
+        stream.labels.extend(missing);
+    }
+    changed
+}
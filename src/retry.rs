@@ -0,0 +1,201 @@
+//! Retrying pushes to Loki with jittered exponential backoff
+
+use axum::body::Body;
+use http::{header::RETRY_AFTER, HeaderMap, Request, StatusCode};
+use http_body_util::{BodyExt, Limited};
+use rand::Rng;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+use crate::shapes::Client;
+
+// how much of a refusal's body is kept to explain it
+const MAX_REASON_BYTES: usize = 64 * 1024;
+
+/// How hard a push is retried, flattened into the consumers' args
+#[derive(clap::Args, Debug, Clone)]
+pub struct RetryArgs {
+    /// Attempts made at a push before giving up on it
+    #[arg(long, default_value = "5")]
+    pub retry_attempts: u32,
+    /// Milliseconds waited after the first failure, doubling with every further one
+    #[arg(long, default_value = "500")]
+    pub retry_backoff: u64,
+    /// Most milliseconds waited between two attempts
+    #[arg(long, default_value = "30000")]
+    pub retry_max_backoff: u64,
+    /// Seconds after which a push is given up on, however many attempts are left
+    #[arg(long, default_value = "300")]
+    pub retry_max_age: u64,
+}
+
+/// How a push ended
+#[derive(Debug)]
+pub enum Outcome {
+    /// Loki took the push
+    Accepted,
+    /// Loki refused the push and would refuse it again, e.g. entries out of order or too old
+    Rejected {
+        status: Option<StatusCode>,
+        reason: String,
+    },
+    /// every attempt failed with something that may clear up later
+    GaveUp { attempts: u32, reason: String },
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Accepted => write!(f, "accepted"),
+            Outcome::Rejected {
+                status: Some(status),
+                reason,
+            } => write!(f, "rejected with {status}: {reason}"),
+            Outcome::Rejected {
+                status: None,
+                reason,
+            } => write!(f, "rejected: {reason}"),
+            Outcome::GaveUp { attempts, reason } => {
+                write!(f, "gave up after {attempts} attempts: {reason}")
+            }
+        }
+    }
+}
+
+impl RetryArgs {
+    /// Send the request `build` makes until Loki accepts or refuses it, or the budget runs out
+    pub async fn send<F>(&self, client: &Client, build: F) -> Outcome
+    where
+        F: Fn() -> Result<Request<Body>, anyhow::Error>,
+    {
+        let started = Instant::now();
+        let max_age = Duration::from_secs(self.retry_max_age);
+        let max_backoff = Duration::from_millis(self.retry_max_backoff);
+        let mut backoff = Duration::from_millis(self.retry_backoff).min(max_backoff);
+        let mut attempt = 1;
+        loop {
+            let req = match build() {
+                Ok(req) => req,
+                Err(e) => {
+                    return Outcome::Rejected {
+                        status: None,
+                        reason: format!("{e:#}"),
+                    }
+                }
+            };
+            let (reason, retry_after) = match client.request(req).await {
+                Ok(resp) if resp.status().is_success() => return Outcome::Accepted,
+                Ok(resp) if retryable(resp.status()) => (
+                    format!("loki answered {}", resp.status()),
+                    retry_after(resp.headers()),
+                ),
+                Ok(resp) => {
+                    let status = resp.status();
+                    let reason = match Limited::new(resp.into_body(), MAX_REASON_BYTES)
+                        .collect()
+                        .await
+                    {
+                        Ok(body) => String::from_utf8_lossy(&body.to_bytes()).trim().to_string(),
+                        Err(_) => String::new(),
+                    };
+                    return Outcome::Rejected {
+                        status: Some(status),
+                        reason,
+                    };
+                }
+                Err(e) => (format!("{e:#}"), None),
+            };
+
+            let delay = retry_after.unwrap_or_else(|| jitter(backoff));
+            if attempt >= self.retry_attempts || !fits(started.elapsed(), delay, max_age) {
+                return Outcome::GaveUp {
+                    attempts: attempt,
+                    reason,
+                };
+            }
+            warn!("push attempt {attempt} failed, retrying in {delay:?}: {reason}");
+            tokio::time::sleep(delay).await;
+            backoff = (backoff * 2).min(max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+/// Throttling and server side failures may clear up, any other refusal won't
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+// `Retry-After` is either a number of seconds or an http date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok(),
+    }
+}
+
+// whether waiting `delay` more still stays within `max_age`, a `Retry-After` too large to add
+// never does
+fn fits(elapsed: Duration, delay: Duration, max_age: Duration) -> bool {
+    elapsed.checked_add(delay).is_some_and(|age| age <= max_age)
+}
+
+// somewhere between half and all of the backoff, so consumers don't retry in lockstep
+fn jitter(backoff: Duration) -> Duration {
+    rand::thread_rng().gen_range(backoff / 2..=backoff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn classifies_statuses() {
+        assert!(retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(retryable(StatusCode::BAD_GATEWAY));
+        assert!(!retryable(StatusCode::BAD_REQUEST));
+        assert!(!retryable(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn reads_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&later).unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn budgets_huge_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("18446744073709551615"),
+        );
+        let delay = retry_after(&headers).unwrap();
+        let max_age = Duration::from_secs(300);
+        assert!(!fits(Duration::from_secs(1), delay, max_age));
+        assert!(fits(
+            Duration::from_secs(1),
+            Duration::from_secs(7),
+            max_age
+        ));
+        assert!(!fits(
+            Duration::from_secs(299),
+            Duration::from_secs(7),
+            max_age
+        ));
+    }
+}
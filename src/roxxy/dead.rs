@@ -0,0 +1,218 @@
+use lapin::message::Delivery;
+use lapin::options::{BasicPublishOptions, QueueBindOptions, QueueDeclareOptions};
+use lapin::types::{AMQPValue, FieldTable, ShortString};
+use lapin::{Channel, ExchangeKind};
+use log::info;
+use oxxy::amqp::{long_string, QueueType, TopologyArgs};
+
+/// Dead-lettering of messages roxxy can't push, flattened into its args
+#[derive(clap::Args, Debug, Clone)]
+pub struct DeadLetterArgs {
+    /// Exchange failed messages are dead-lettered to, declared with `<queue>.retry` and `<queue>.quarantine`
+    #[arg(long)]
+    pub dead_letter_exchange: Option<String>,
+    /// Seconds a message whose push failed waits in `<queue>.retry`, or is held by roxxy without
+    /// a dead-letter exchange, before it is delivered again
+    #[arg(long, default_value = "30")]
+    pub requeue_delay: u64,
+    /// Times a message goes through `<queue>.retry` before it is quarantined
+    #[arg(long, default_value = "5")]
+    pub max_requeues: i64,
+}
+
+/// The dead-letter exchange and the queues bound to it
+pub struct DeadLetters {
+    channel: Channel,
+    exchange: String,
+    queue: String,
+    max_requeues: i64,
+}
+
+impl DeadLetterArgs {
+    /// Stream queues can't dead-letter, so they can't be given a dead-letter exchange
+    pub fn validate(&self, topology: &TopologyArgs) -> Result<(), String> {
+        match (&self.dead_letter_exchange, topology.queue_type) {
+            (Some(_), QueueType::Stream) => {
+                Err("--dead-letter-exchange can't be used with --queue-type stream".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Declare the dead-letter exchange and its queues for messages consumed from `queue`
+    ///
+    /// `<queue>.retry` holds failed messages for `--requeue-delay` and then dead-letters them through
+    /// the default exchange straight back to `queue`, so other queues bound to the original exchange
+    /// don't get another copy. `<queue>.quarantine` keeps what won't be retried. Both are classic
+    /// queues, declared durable and passive as `topology` asks.
+    pub async fn declare(
+        &self,
+        channel: &Channel,
+        topology: &TopologyArgs,
+        queue: &str,
+    ) -> Result<Option<DeadLetters>, anyhow::Error> {
+        let Some(dead) = &self.dead_letter_exchange else {
+            return Ok(None);
+        };
+        channel
+            .exchange_declare(
+                dead,
+                ExchangeKind::Direct,
+                topology.exchange.exchange_options(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        let mut retry = FieldTable::default();
+        retry.insert(
+            "x-message-ttl".into(),
+            AMQPValue::LongLongInt(self.requeue_delay as i64 * 1000),
+        );
+        retry.insert("x-dead-letter-exchange".into(), long_string(""));
+        retry.insert("x-dead-letter-routing-key".into(), long_string(queue));
+        for (name, arguments) in [
+            (retry_queue(queue), retry),
+            (quarantine_queue(queue), FieldTable::default()),
+        ] {
+            let options = QueueDeclareOptions {
+                durable: topology.exchange.durable,
+                ..topology.queue_options()
+            };
+            channel.queue_declare(&name, options, arguments).await?;
+            if topology.exchange.passive {
+                continue;
+            }
+            channel
+                .queue_bind(
+                    &name,
+                    dead,
+                    &name,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+        info!("Dead-lettering {} to {}", queue, dead);
+
+        Ok(Some(DeadLetters {
+            channel: channel.clone(),
+            exchange: dead.clone(),
+            queue: queue.to_string(),
+            max_requeues: self.max_requeues,
+        }))
+    }
+}
+
+impl DeadLetters {
+    /// Arguments the consumed queue is declared with, so that rejected messages go to `<queue>.retry`
+    pub fn queue_arguments(&self) -> FieldTable {
+        let mut arguments = FieldTable::default();
+        arguments.insert("x-dead-letter-exchange".into(), long_string(&self.exchange));
+        arguments.insert(
+            "x-dead-letter-routing-key".into(),
+            long_string(&retry_queue(&self.queue)),
+        );
+        arguments
+    }
+
+    /// Whether a message has been through the retry queue as often as it may
+    pub fn exhausted(&self, delivery: &Delivery) -> Option<i64> {
+        let requeues = requeues(delivery.properties.headers().as_ref(), &self.queue);
+        (requeues >= self.max_requeues).then_some(requeues)
+    }
+
+    /// Republish a message to `<queue>.quarantine` with headers saying why it was set aside
+    pub async fn quarantine(
+        &self,
+        delivery: &Delivery,
+        reason: &str,
+        status: Option<u16>,
+    ) -> Result<(), anyhow::Error> {
+        let mut headers = delivery.properties.headers().clone().unwrap_or_default();
+        headers.insert("x-quarantine-reason".into(), long_string(reason));
+        headers.insert("x-quarantine-queue".into(), long_string(&self.queue));
+        headers.insert(
+            "x-quarantine-routing-key".into(),
+            long_string(delivery.routing_key.as_str()),
+        );
+        if let Some(status) = status {
+            headers.insert("x-quarantine-status".into(), AMQPValue::ShortUInt(status));
+        }
+        self.channel
+            .basic_publish(
+                &self.exchange,
+                &quarantine_queue(&self.queue),
+                BasicPublishOptions::default(),
+                &delivery.data,
+                delivery.properties.clone().with_headers(headers),
+            )
+            .await?
+            .await?;
+        Ok(())
+    }
+}
+
+fn retry_queue(queue: &str) -> String {
+    format!("{queue}.retry")
+}
+
+fn quarantine_queue(queue: &str) -> String {
+    format!("{queue}.quarantine")
+}
+
+/// How often the broker has dead-lettered a message out of `queue`, from its `x-death` header
+fn requeues(headers: Option<&FieldTable>, queue: &str) -> i64 {
+    let deaths = headers
+        .and_then(|h| h.inner().get(&ShortString::from("x-death")))
+        .and_then(AMQPValue::as_array);
+    let Some(deaths) = deaths else {
+        return 0;
+    };
+    deaths
+        .as_slice()
+        .iter()
+        .filter_map(AMQPValue::as_field_table)
+        .filter(|death| {
+            death
+                .inner()
+                .get(&ShortString::from("queue"))
+                .and_then(AMQPValue::as_long_string)
+                .is_some_and(|q| q.as_bytes() == queue.as_bytes())
+        })
+        .filter_map(|death| {
+            let count = death.inner().get(&ShortString::from("count"))?;
+            count
+                .as_long_long_int()
+                .or_else(|| count.as_long_int().map(i64::from))
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::types::FieldArray;
+
+    fn death(queue: &str, count: i64) -> AMQPValue {
+        let mut death = FieldTable::default();
+        death.insert("queue".into(), long_string(queue));
+        death.insert("reason".into(), long_string("rejected"));
+        death.insert("count".into(), AMQPValue::LongLongInt(count));
+        AMQPValue::FieldTable(death)
+    }
+
+    #[test]
+    fn counts_requeues_from_x_death() {
+        assert_eq!(requeues(None, "logs"), 0);
+        let mut headers = FieldTable::default();
+        headers.insert(
+            "x-death".into(),
+            AMQPValue::FieldArray(FieldArray::from(vec![
+                death("logs", 3),
+                death("logs.retry", 3),
+            ])),
+        );
+        assert_eq!(requeues(Some(&headers), "logs"), 3);
+        assert_eq!(requeues(Some(&headers), "other"), 0);
+    }
+}
@@ -1,20 +1,36 @@
-use axum::{body::Body, extract::Request, response::IntoResponse};
+mod dead;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+};
+
+use clap::error::ErrorKind;
 use clap::Parser;
 
 use http::Method;
-use hyper::StatusCode;
-use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
-use lapin::options::{ExchangeDeclareOptions, QueueBindOptions};
 use lapin::{
-    message::DeliveryResult,
-    options::{BasicAckOptions, BasicConsumeOptions, QueueDeclareOptions},
-    types::FieldTable,
-    Connection, ConnectionProperties, ExchangeKind,
+    message::{Delivery, DeliveryResult},
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions, BasicRejectOptions,
+    },
+    types::{AMQPValue, FieldTable, ShortString},
+    Connection, ConnectionProperties,
+};
+use log::{debug, error, info, warn};
+use oxxy::amqp::TopologyArgs;
+use oxxy::batch::{BatchArgs, Batcher, Flush};
+use oxxy::retry::{Outcome, RetryArgs};
+use oxxy::shapes::{
+    sniff_encoding, Client, ClientArgs, Format, LokiAuth, LokiAuthArgs, OutputFormat, Payload,
+    PushRequest, ORG_ID_HEADER,
 };
-use log::debug;
-use oxxy::shapes::{Client, LogMessage};
 
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::dead::{DeadLetterArgs, DeadLetters};
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
@@ -40,11 +56,47 @@ struct Args {
 
     #[arg(short, long, default_value = "false")]
     strict: bool,
+
+    /// Re-encode pushes before forwarding them to loki
+    #[arg(long, value_enum, default_value = "passthrough")]
+    output_format: OutputFormat,
+
+    /// Unacked messages the broker hands each consumer channel at once
+    #[arg(long, default_value = "100")]
+    prefetch: u16,
+
+    /// Messages being pushed or waiting in a batch at once, across all consumers
+    #[arg(long, default_value = "100")]
+    in_flight: usize,
+
+    /// Channels consuming the queue
+    #[arg(long, default_value = "1")]
+    consumers: usize,
+
+    #[command(flatten)]
+    topology: TopologyArgs,
+    #[command(flatten)]
+    client: ClientArgs,
+    #[command(flatten)]
+    loki_auth: LokiAuthArgs,
+    #[command(flatten)]
+    batch: BatchArgs,
+    #[command(flatten)]
+    retry: RetryArgs,
+    #[command(flatten)]
+    dead_letter: DeadLetterArgs,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let args = Args::parse();
+    if let Err(e) = args
+        .topology
+        .validate()
+        .and_then(|()| args.dead_letter.validate(&args.topology))
+    {
+        clap::Error::raw(ErrorKind::ArgumentConflict, format!("{e}\n")).exit();
+    }
     env_logger::init();
 
     let options = ConnectionProperties::default()
@@ -57,50 +109,76 @@ async fn main() -> Result<(), anyhow::Error> {
     let connection = Connection::connect(&args.rmq_uri, options).await.unwrap();
     let channel = connection.create_channel().await.unwrap();
 
-    channel
-        .exchange_declare(
-            &args.exchange,                    // exchange name
-            ExchangeKind::Direct,              // type of exchange
-            ExchangeDeclareOptions::default(), // default options
-            FieldTable::default(),             // no extra parameters
-        )
+    args.topology
+        .exchange
+        .declare_exchange(&channel, &args.exchange)
         .await?;
 
-    let _queue = channel
-        .queue_declare(
-            &args.queue,
-            QueueDeclareOptions::default(),
-            FieldTable::default(),
-        )
-        .await?;
+    let dead = args
+        .dead_letter
+        .declare(&channel, &args.topology, &args.queue)
+        .await?
+        .map(Arc::new);
 
-    channel
-        .queue_bind(
-            &args.queue,       // queue name
-            &args.exchange,    // exchange name
-            &args.routing_key, // routing key
-            QueueBindOptions::default(),
-            FieldTable::default(),
-        )
+    let arguments = dead
+        .as_ref()
+        .map(|dead| dead.queue_arguments())
+        .unwrap_or_default();
+    args.topology
+        .declare_queue(&channel, &args.queue, arguments)
         .await?;
-
-    let consumer = channel
-        .basic_consume(
-            &args.queue,
-            &args.routing_key,
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        )
+    args.topology
+        .bind(&channel, &args.queue, &args.exchange, &args.routing_key)
         .await?;
 
-    let url_for_closure = Arc::new(args.loki_url.clone());
+    let loki = Arc::new(Loki {
+        url: args.loki_url.clone(),
+        client: args.client.client()?,
+        auth: args.loki_auth.auth()?,
+        retry: args.retry.clone(),
+    });
+    let requeue_delay = Duration::from_secs(args.dead_letter.requeue_delay);
+    let batcher = {
+        let (loki, dead) = (loki.clone(), dead.clone());
+        Batcher::spawn(
+            args.batch.clone(),
+            args.output_format,
+            move |batch: Flush<(Arc<Delivery>, OwnedSemaphorePermit)>| {
+                let (loki, dead) = (loki.clone(), dead.clone());
+                async move {
+                    let outcome = match batch.payload {
+                        Ok(payload) => loki.push(payload, batch.tenant.as_deref()).await,
+                        // encoding it again won't go any better
+                        Err(e) => Outcome::Rejected {
+                            status: None,
+                            reason: format!("unable to encode the batch: {e:#}"),
+                        },
+                    };
+                    if !matches!(outcome, Outcome::Accepted) {
+                        error!("Push of {} entries failed, {}", batch.entries, outcome);
+                    }
+                    let deliveries: Vec<&Delivery> = batch
+                        .tokens
+                        .iter()
+                        .map(|(delivery, _)| &**delivery)
+                        .collect();
+                    settle(&deliveries, &outcome, dead.as_deref(), requeue_delay).await;
+                }
+            },
+        )
+    };
 
-    consumer.set_delegate(move |delivery: DeliveryResult| {
-        let loki_url_ = Arc::clone(&url_for_closure);
+    let in_flight = Arc::new(Semaphore::new(args.in_flight));
+    let strict = args.strict;
+    let delegate = move |delivery: DeliveryResult| {
+        let loki = loki.clone();
+        let batcher = batcher.clone();
+        let dead = dead.clone();
+        let in_flight = in_flight.clone();
         async move {
             let delivery = match delivery {
                 // Carries the delivery alongside its channel
-                Ok(Some(delivery)) => delivery,
+                Ok(Some(delivery)) => Arc::new(delivery),
                 // The consumer got canceled
                 Ok(None) => return,
                 // Carries the error and is always followed by Ok(None)
@@ -110,79 +188,242 @@ async fn main() -> Result<(), anyhow::Error> {
                 }
             };
 
+            // held until the message is settled, the semaphore is never closed
+            let permit = in_flight.acquire_owned().await.expect("in-flight limit");
+
             // info!("got message {:?}", &delivery);
             let payload = &delivery.data;
-            if let Ok(message) = String::from_utf8(payload.clone()) {
-                let messagey = match serde_json::from_str::<LogMessage>(&message) {
-                    Ok(log_message) => {
-                        debug!("Successfully deserialized log message: {:?}", log_message);
-                        true
-                    }
-                    Err(e) => {
-                        debug!("Failed to deserialize log message: {}", e);
-                        false
+            // tenant loxxy attached to the message, passed on to loki
+            let tenant = delivery
+                .properties
+                .headers()
+                .as_ref()
+                .and_then(|h| h.inner().get(&ShortString::from(ORG_ID_HEADER)).cloned())
+                .and_then(|v| match v {
+                    AMQPValue::LongString(s) => Some(s.to_string()),
+                    _ => None,
+                });
+            let content_encoding = content_encoding(&delivery);
+            let decoded = decode(&delivery, content_encoding.as_deref());
+            let (payload, problem) = match decoded {
+                Ok((push, format)) => {
+                    let received = Payload {
+                        body: payload.clone(),
+                        format,
+                        content_encoding,
+                    };
+                    match push.validate() {
+                        Ok(()) => {
+                            debug!("Successfully decoded {:?} push: {:?}", format, push);
+                            // settled by the batcher once the batch has been pushed
+                            if let Err(e) = batcher
+                                .add(tenant.clone(), push, received, (delivery.clone(), permit))
+                                .await
+                            {
+                                let reason = format!("unable to queue push: {e}");
+                                let outcome = Outcome::GaveUp {
+                                    attempts: 0,
+                                    reason,
+                                };
+                                settle(&[&delivery], &outcome, dead.as_deref(), requeue_delay)
+                                    .await;
+                            }
+                            return;
+                        }
+                        Err(e) => {
+                            debug!("Decoded {:?} push is not valid: {}", format, e);
+                            (received, format!("invalid push: {e}"))
+                        }
                     }
-                };
-                #[allow(clippy::nonminimal_bool)]
-                if (messagey && args.strict) || !args.strict {
-                    let client: Client =
-                        hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
-                            .build(HttpConnector::new());
-                    let req = Request::builder()
-                        .header("content-type", "application/json")
-                        .header("user-agent", "oxxy-roxxy")
-                        .method(Method::POST)
-                        .uri(loki_url_.as_str())
-                        .body(Body::from(message))
-                        .expect("Request builder error");
-                    let _resp = client
-                        .request(req)
-                        .await
-                        .map_err(|_| StatusCode::BAD_REQUEST)
-                        .expect("bad req")
-                        .into_response();
-
-                    // warn!("{:?}", body.collect().await.expect("b").to_bytes());
-                    //forward
-                };
-                return;
-
-                // info!("Received message: {}", message);
-            } else {
-                debug!(
-                    "Received non-UTF-8 message, likely protobuf, passing along raw: {:?}",
-                    &payload
-                );
-                // let s = String::from_utf8_lossy(&payload).to_string();
-                // info!("{:?}", s);
-                let client: Client =
-                    hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
-                        .build(HttpConnector::new());
-                let payload_ = payload.clone();
-                let req = Request::builder()
-                    .header("Content-Type", "application/x-protobuf")
-                    .header("user-agent", "oxxy-roxxy")
-                    .method(Method::POST)
-                    .uri(loki_url_.as_str())
-                    .body(Body::from(payload_))
-                    .expect("Request builder error");
-                let _resp = client
-                    .request(req)
-                    .await
-                    .map_err(|_| StatusCode::BAD_REQUEST)
-                    .expect("bad req")
-                    .into_response();
+                }
+                Err(e) => {
+                    debug!("Failed to decode log message: {}", e);
+                    // pass it along as whatever it looks most like
+                    let format = match std::str::from_utf8(payload) {
+                        Ok(_) => Format::Json,
+                        Err(_) => Format::Protobuf,
+                    };
+                    let payload = Payload {
+                        body: payload.clone(),
+                        format,
+                        content_encoding,
+                    };
+                    (payload, format!("undecodable push: {e}"))
+                }
+            };
+            // non-conforming messages skip batching, and are refused in strict mode
+            let outcome = match strict {
+                true => Outcome::Rejected {
+                    status: None,
+                    reason: problem,
+                },
+                false => loki.push(payload, tenant.as_deref()).await,
+            };
+            if !matches!(outcome, Outcome::Accepted) {
+                error!("Message not pushed, {}", outcome);
             }
-
-            // Do something with the delivery data (The message payload)
-
-            let _ = &delivery
-                .ack(BasicAckOptions::default())
-                .await
-                .expect("Failed to ack send_webhook_event message");
+            settle(&[&delivery], &outcome, dead.as_deref(), requeue_delay).await;
+            drop(permit);
         }
-    });
+    };
+
+    for i in 0..args.consumers.max(1) {
+        let channel = match i {
+            0 => channel.clone(),
+            _ => connection.create_channel().await?,
+        };
+        channel
+            .basic_qos(args.prefetch, BasicQosOptions::default())
+            .await?;
+        let consumer = channel
+            .basic_consume(
+                &args.queue,
+                &args.routing_key,
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        consumer.set_delegate(delegate.clone());
+    }
+    info!(
+        "Consuming {} on {} channels",
+        args.queue,
+        args.consumers.max(1)
+    );
 
     std::future::pending::<()>().await;
     Ok(())
 }
+
+// loxxy labels what it publishes, anything else has to be sniffed
+fn content_encoding(delivery: &Delivery) -> Option<String> {
+    delivery
+        .properties
+        .content_encoding()
+        .as_ref()
+        .map(|ce| ce.to_string())
+        .or_else(|| sniff_encoding(&delivery.data).map(str::to_string))
+}
+
+fn decode(
+    delivery: &Delivery,
+    content_encoding: Option<&str>,
+) -> Result<(PushRequest, Format), anyhow::Error> {
+    match delivery.properties.content_type() {
+        Some(ct) => PushRequest::decode_http(&delivery.data, Some(ct.as_str()), content_encoding),
+        None => PushRequest::sniff(&delivery.data),
+    }
+}
+
+// the label sets of a message's streams, to tell which logs were dropped
+fn labels(delivery: &Delivery) -> String {
+    match decode(delivery, content_encoding(delivery).as_deref()) {
+        Ok((push, _)) => push
+            .streams
+            .iter()
+            .map(|stream| stream.labels.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        Err(_) => "an undecodable push".to_string(),
+    }
+}
+
+/// Settle the deliveries a push carried by how it ended
+///
+/// Messages are acked once Loki has them. Otherwise they are held for `requeue_delay` and requeued
+/// when Loki may take them later, and dropped when it never will. With `--dead-letter-exchange`
+/// they wait in the retry queue instead until they have been requeued `--max-requeues` times, and
+/// are quarantined after that.
+async fn settle(
+    deliveries: &[&Delivery],
+    outcome: &Outcome,
+    dead: Option<&DeadLetters>,
+    requeue_delay: Duration,
+) {
+    if let (Outcome::GaveUp { .. }, None) = (outcome, dead) {
+        // the broker would hand them straight back, to fail again right away
+        tokio::time::sleep(requeue_delay).await;
+    }
+    for delivery in deliveries {
+        settle_one(delivery, outcome, dead).await;
+    }
+}
+
+async fn settle_one(delivery: &Delivery, outcome: &Outcome, dead: Option<&DeadLetters>) {
+    let acker = &delivery.acker;
+    let reject = BasicRejectOptions { requeue: false };
+    let settled = match (outcome, dead) {
+        (Outcome::Accepted, _) => acker.ack(BasicAckOptions::default()).await,
+        (Outcome::GaveUp { .. }, None) => {
+            let options = BasicNackOptions {
+                requeue: true,
+                ..Default::default()
+            };
+            acker.nack(options).await
+        }
+        (Outcome::Rejected { .. }, None) => {
+            warn!("Dropping message with {}, {}", labels(delivery), outcome);
+            acker.reject(reject).await
+        }
+        (Outcome::GaveUp { reason, .. }, Some(dead)) => match dead.exhausted(delivery) {
+            // dead-lettered into the retry queue by the broker
+            None => acker.reject(reject).await,
+            Some(requeues) => {
+                let reason = format!("requeued {requeues} times: {reason}");
+                quarantine(delivery, dead, &reason, None).await
+            }
+        },
+        (Outcome::Rejected { status, reason }, Some(dead)) => {
+            quarantine(delivery, dead, reason, status.map(|s| s.as_u16())).await
+        }
+    };
+    if let Err(e) = settled {
+        error!("Failed to settle message: {}", e);
+    }
+}
+
+async fn quarantine(
+    delivery: &Delivery,
+    dead: &DeadLetters,
+    reason: &str,
+    status: Option<u16>,
+) -> Result<(), lapin::Error> {
+    match dead.quarantine(delivery, reason, status).await {
+        Ok(()) => delivery.acker.ack(BasicAckOptions::default()).await,
+        Err(e) => {
+            error!("Failed to quarantine message, retrying it later: {:#}", e);
+            let reject = BasicRejectOptions { requeue: false };
+            delivery.acker.reject(reject).await
+        }
+    }
+}
+
+/// Where and how pushes are sent on
+struct Loki {
+    url: String,
+    client: Client,
+    auth: LokiAuth,
+    retry: RetryArgs,
+}
+
+impl Loki {
+    async fn push(&self, payload: Payload, tenant: Option<&str>) -> Outcome {
+        let body = Bytes::from(payload.body);
+        self.retry
+            .send(&self.client, || {
+                let mut req = Request::builder()
+                    .header("content-type", payload.format.content_type())
+                    .header("user-agent", "oxxy-roxxy")
+                    .method(Method::POST)
+                    .uri(self.url.as_str());
+                if let Some(encoding) = &payload.content_encoding {
+                    req = req.header("content-encoding", encoding);
+                }
+                Ok(self
+                    .auth
+                    .apply(req, tenant)
+                    .body(Body::from(body.clone()))?)
+            })
+            .await
+    }
+}
@@ -1,12 +1,842 @@
+use anyhow::anyhow;
+use anyhow::Context;
 use axum::body::Body;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use http::header::{AUTHORIZATION, PROXY_AUTHORIZATION};
+use http::{request, HeaderMap, HeaderValue, Request, Response};
+use hyper::body::Incoming;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper_util::client::legacy::connect::HttpConnector;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-
-pub type Client = hyper_util::client::legacy::Client<HttpConnector, Body>;
-type Values = Vec<(String, String)>;
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LogMessage {
-    stream: HashMap<String, String>,
-    values: Values,
+use hyper_util::rt::TokioExecutor;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::logproto;
+
+pub type Connector = HttpsConnector<HttpConnector>;
+
+/// A pooled http(s) client for Loki whose requests give up after a timeout
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: hyper_util::client::legacy::Client<Connector, Body>,
+    timeout: Duration,
+}
+
+impl Client {
+    /// Send a request, the timeout covers everything up to the response head
+    pub async fn request(&self, req: Request<Body>) -> Result<Response<Incoming>, anyhow::Error> {
+        match tokio::time::timeout(self.timeout, self.inner.request(req)).await {
+            Ok(resp) => Ok(resp?),
+            Err(_) => Err(anyhow!("no response within {:?}", self.timeout)),
+        }
+    }
+}
+
+/// How the binaries connect to Loki, flattened into each one's args
+#[derive(clap::Args, Debug, Clone)]
+pub struct ClientArgs {
+    /// PEM CA bundle trusted for https Loki urls, in addition to the bundled web roots
+    #[arg(long)]
+    pub loki_ca: Option<PathBuf>,
+    /// PEM certificate chain presented to Loki
+    #[arg(long, requires = "loki_client_key")]
+    pub loki_client_cert: Option<PathBuf>,
+    #[arg(long, requires = "loki_client_cert")]
+    pub loki_client_key: Option<PathBuf>,
+    /// Accept any certificate from Loki, for testing only
+    #[arg(long)]
+    pub loki_insecure: bool,
+
+    /// Idle connections kept open to Loki
+    #[arg(long, default_value = "32")]
+    pub loki_pool_size: usize,
+    /// Seconds an idle pooled connection is kept before it is closed
+    #[arg(long, default_value = "90")]
+    pub loki_pool_idle: u64,
+    /// Seconds between TCP keepalive probes on connections to Loki
+    #[arg(long, default_value = "60")]
+    pub loki_keepalive: u64,
+    /// Seconds allowed for connecting to Loki
+    #[arg(long, default_value = "10")]
+    pub loki_connect_timeout: u64,
+    /// Seconds allowed for Loki to answer a request
+    #[arg(long, default_value = "30")]
+    pub loki_timeout: u64,
+}
+
+impl ClientArgs {
+    // speaks plain http and https with the configured trust and identity
+    fn connector(&self) -> Result<Connector, anyhow::Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = if self.loki_insecure {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyCert(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            if let Some(ca) = &self.loki_ca {
+                for cert in certs(ca)? {
+                    roots.add(cert)?;
+                }
+            }
+            builder.with_root_certificates(roots)
+        };
+        let config = match (&self.loki_client_cert, &self.loki_client_key) {
+            (Some(cert), Some(key)) => {
+                let raw =
+                    std::fs::read(key).with_context(|| format!("reading {}", key.display()))?;
+                let key = rustls_pemfile::private_key(&mut raw.as_slice())?
+                    .ok_or_else(|| anyhow!("no private key in {}", key.display()))?;
+                builder.with_client_auth_cert(certs(cert)?, key)?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_keepalive(Some(Duration::from_secs(self.loki_keepalive)));
+        http.set_connect_timeout(Some(Duration::from_secs(self.loki_connect_timeout)));
+        Ok(HttpsConnectorBuilder::new()
+            .with_tls_config(config)
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(http))
+    }
+
+    /// A pooled client, build it once and clone it wherever Loki is talked to
+    pub fn client(&self) -> Result<Client, anyhow::Error> {
+        let inner = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
+            .pool_max_idle_per_host(self.loki_pool_size)
+            .pool_idle_timeout(Duration::from_secs(self.loki_pool_idle))
+            .build(self.connector()?);
+        Ok(Client {
+            inner,
+            timeout: Duration::from_secs(self.loki_timeout),
+        })
+    }
+}
+
+/// Credentials and tenant the consumers push to Loki with, flattened into each one's args
+#[derive(clap::Args, Clone, Default)]
+pub struct LokiAuthArgs {
+    /// User for basic auth against Loki
+    #[arg(long, requires = "loki_password")]
+    pub loki_user: Option<String>,
+    #[arg(long, env = "LOKI_PASSWORD", hide_env_values = true)]
+    pub loki_password: Option<String>,
+    /// File holding a bearer token for Loki, re-read every `--loki-token-refresh` seconds
+    #[arg(long, conflicts_with = "loki_user")]
+    pub loki_token_file: Option<PathBuf>,
+    #[arg(long, default_value = "60")]
+    pub loki_token_refresh: u64,
+    /// Tenant sent as X-Scope-OrgID for messages that don't carry their own
+    #[arg(long, env = "LOKI_ORG_ID")]
+    pub loki_org_id: Option<String>,
+}
+
+// args get logged at startup, so the password is left out
+impl fmt::Debug for LokiAuthArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LokiAuthArgs")
+            .field("loki_user", &self.loki_user)
+            .field(
+                "loki_password",
+                &self.loki_password.as_ref().map(|_| "<redacted>"),
+            )
+            .field("loki_token_file", &self.loki_token_file)
+            .field("loki_token_refresh", &self.loki_token_refresh)
+            .field("loki_org_id", &self.loki_org_id)
+            .finish()
+    }
+}
+
+impl LokiAuthArgs {
+    /// Load the credentials, a token file is refreshed on a background task from then on
+    pub fn auth(&self) -> Result<LokiAuth, anyhow::Error> {
+        let authorization = match (&self.loki_user, &self.loki_password, &self.loki_token_file) {
+            (Some(user), Some(password), _) => {
+                let credentials = STANDARD.encode(format!("{user}:{password}"));
+                Some(sensitive(&format!("Basic {credentials}"))?)
+            }
+            (_, _, Some(path)) => Some(bearer(path)?),
+            _ => None,
+        };
+        let authorization = authorization.map(|value| Arc::new(RwLock::new(value)));
+        if let (Some(path), Some(current)) = (&self.loki_token_file, &authorization) {
+            let (path, current) = (path.clone(), current.clone());
+            let interval = Duration::from_secs(self.loki_token_refresh);
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    match bearer(&path) {
+                        Ok(value) => *current.write().unwrap() = value,
+                        Err(e) => warn!("keeping previous loki token, reload failed: {:#}", e),
+                    }
+                }
+            });
+        }
+        Ok(LokiAuth {
+            authorization,
+            org_id: self.loki_org_id.clone(),
+        })
+    }
+}
+
+/// Applies `LokiAuthArgs` to outgoing requests
+#[derive(Debug, Clone, Default)]
+pub struct LokiAuth {
+    authorization: Option<Arc<RwLock<HeaderValue>>>,
+    org_id: Option<String>,
+}
+
+impl LokiAuth {
+    /// Add the credentials, and the message's `tenant` or else the configured one
+    pub fn apply(&self, mut req: request::Builder, tenant: Option<&str>) -> request::Builder {
+        if let Some(authorization) = &self.authorization {
+            req = req.header(AUTHORIZATION, authorization.read().unwrap().clone());
+        }
+        match tenant.or(self.org_id.as_deref()) {
+            Some(tenant) => req.header(ORG_ID_HEADER, tenant),
+            None => req,
+        }
+    }
+
+    /// Replace whatever credentials a proxied request carries with the configured ones
+    pub fn authorize(&self, headers: &mut HeaderMap) {
+        headers.remove(AUTHORIZATION);
+        headers.remove(PROXY_AUTHORIZATION);
+        if let Some(authorization) = &self.authorization {
+            headers.insert(AUTHORIZATION, authorization.read().unwrap().clone());
+        }
+    }
+
+    /// The tenant for requests that don't carry their own
+    pub fn org_id(&self) -> Option<&str> {
+        self.org_id.as_deref()
+    }
+}
+
+fn sensitive(value: &str) -> Result<HeaderValue, anyhow::Error> {
+    let mut value = HeaderValue::from_str(value)?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+fn bearer(path: &Path) -> Result<HeaderValue, anyhow::Error> {
+    let token =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    sensitive(&format!("Bearer {}", token.trim()))
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, anyhow::Error> {
+    let raw = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut raw.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+// `--loki-insecure`, trusts any certificate but still checks the handshake signatures
+#[derive(Debug)]
+struct AnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Header Loki reads the tenant (org ID) from
+pub const ORG_ID_HEADER: &str = "X-Scope-OrgID";
+
+/// The wire formats Loki accepts pushes in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    Json,
+    /// snappy compressed `logproto.PushRequest`
+    Protobuf,
+}
+
+impl Format {
+    /// Loki only treats `application/json` bodies as JSON, anything else is protobuf
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type
+            .and_then(|ct| ct.split(';').next())
+            .map(str::trim)
+        {
+            Some(ct) if ct.eq_ignore_ascii_case("application/json") => Format::Json,
+            _ => Format::Protobuf,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Protobuf => "application/x-protobuf",
+        }
+    }
+}
+
+/// What pushes are re-encoded as before they are forwarded or published
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// leave the body exactly as it was received
+    Passthrough,
+    Json,
+    /// snappy compressed protobuf
+    Protobuf,
+}
+
+impl OutputFormat {
+    /// The format a push received as `source` leaves in
+    pub fn resolve(&self, source: Format) -> Format {
+        match self {
+            OutputFormat::Passthrough => source,
+            OutputFormat::Json => Format::Json,
+            OutputFormat::Protobuf => Format::Protobuf,
+        }
+    }
+
+    /// Re-encode a decoded `push`, or hand back the payload it was `received` as for passthrough
+    pub fn convert(&self, push: &PushRequest, received: Payload) -> Result<Payload, anyhow::Error> {
+        if *self == OutputFormat::Passthrough {
+            return Ok(received);
+        }
+        let format = self.resolve(received.format);
+        Ok(Payload {
+            body: push.encode(format)?,
+            format,
+            content_encoding: None,
+        })
+    }
+}
+
+/// A push body ready to send on, with what the content type and encoding headers should say
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payload {
+    pub body: Vec<u8>,
+    pub format: Format,
+    pub content_encoding: Option<String>,
+}
+
+/// Most bytes a compressed push may expand to, so a small bomb can't exhaust memory
+pub const MAX_DECODED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Undo a `Content-Encoding`, Loki accepts gzip and deflate compressed bodies
+pub fn decompress<'a>(
+    body: &'a [u8],
+    content_encoding: Option<&str>,
+) -> Result<Cow<'a, [u8]>, anyhow::Error> {
+    let mut raw = vec![];
+    // one byte past the limit tells a body that is exactly at it from one that is over
+    let take = MAX_DECODED_BYTES as u64 + 1;
+    match content_encoding.map(str::trim) {
+        None | Some("") => return Ok(Cow::Borrowed(body)),
+        Some(e) if e.eq_ignore_ascii_case("identity") => return Ok(Cow::Borrowed(body)),
+        Some(e) if e.eq_ignore_ascii_case("gzip") => {
+            GzDecoder::new(body).take(take).read_to_end(&mut raw)?
+        }
+        Some(e) if e.eq_ignore_ascii_case("deflate") => {
+            ZlibDecoder::new(body).take(take).read_to_end(&mut raw)?
+        }
+        Some(other) => return Err(anyhow!("unsupported content encoding {other}")),
+    };
+    if raw.len() > MAX_DECODED_BYTES {
+        return Err(anyhow!(
+            "body decodes to more than {MAX_DECODED_BYTES} bytes"
+        ));
+    }
+    Ok(Cow::Owned(raw))
+}
+
+/// Guess the `Content-Encoding` of a body that arrived without headers
+pub fn sniff_encoding(body: &[u8]) -> Option<&'static str> {
+    body.starts_with(&[0x1f, 0x8b]).then_some("gzip")
+}
+
+/// A Loki push body, `{"streams": [...]}`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PushRequest {
+    pub streams: Vec<Stream>,
+}
+
+/// One label set and the entries pushed for it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stream {
+    #[serde(rename = "stream")]
+    pub labels: Labels,
+    pub values: Vec<Entry>,
+}
+
+/// A log line, serialized the way Loki's JSON API expects it
+///
+/// `["<unix nanos>", "<line>"]` or `["<unix nanos>", "<line>", {<structured metadata>}]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub timestamp: Timestamp,
+    pub line: String,
+    pub structured_metadata: Labels,
+}
+
+/// Label names and values, ordered by name so equal sets compare and hash equal
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Labels(BTreeMap<String, String>);
+
+/// Nanoseconds since the unix epoch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Timestamp(i64);
+
+impl PushRequest {
+    pub fn decode(body: &[u8], format: Format) -> Result<Self, anyhow::Error> {
+        match format {
+            Format::Json => Ok(serde_json::from_slice(body)?),
+            Format::Protobuf => logproto::PushRequest::decode_snappy(body)?.try_into(),
+        }
+    }
+
+    pub fn encode(&self, format: Format) -> Result<Vec<u8>, anyhow::Error> {
+        match format {
+            Format::Json => Ok(serde_json::to_vec(self)?),
+            Format::Protobuf => logproto::PushRequest::from(self).encode_snappy(),
+        }
+    }
+
+    /// Decode a body as sent over HTTP, honouring its content type and encoding
+    pub fn decode_http(
+        body: &[u8],
+        content_type: Option<&str>,
+        content_encoding: Option<&str>,
+    ) -> Result<(Self, Format), anyhow::Error> {
+        let format = Format::from_content_type(content_type);
+        let raw = decompress(body, content_encoding)?;
+        Ok((PushRequest::decode(&raw, format)?, format))
+    }
+
+    /// Decode a body of unknown format, for transports that carry no content type
+    pub fn sniff(body: &[u8]) -> Result<(Self, Format), anyhow::Error> {
+        if let Some(encoding) = sniff_encoding(body) {
+            return PushRequest::sniff(&decompress(body, Some(encoding))?);
+        }
+        match PushRequest::decode(body, Format::Json) {
+            Ok(push) => Ok((push, Format::Json)),
+            Err(json) => PushRequest::decode(body, Format::Protobuf)
+                .map(|push| (push, Format::Protobuf))
+                .map_err(|proto| anyhow!("neither json ({json}) nor protobuf ({proto})")),
+        }
+    }
+
+    /// Check the push against the rules Loki itself would reject it for
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.streams.is_empty() {
+            return Err(anyhow!("push contains no streams"));
+        }
+        for stream in &self.streams {
+            stream.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> usize {
+        self.streams.iter().map(|s| s.values.len()).sum()
+    }
+}
+
+impl Stream {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.labels.is_empty() {
+            return Err(anyhow!("stream has no labels"));
+        }
+        self.labels.validate()?;
+        for entry in &self.values {
+            entry.structured_metadata.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl Entry {
+    pub fn new(timestamp: Timestamp, line: impl Into<String>) -> Self {
+        Entry {
+            timestamp,
+            line: line.into(),
+            structured_metadata: Labels::default(),
+        }
+    }
+}
+
+impl Serialize for Entry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.structured_metadata.is_empty() {
+            (&self.timestamp, &self.line).serialize(serializer)
+        } else {
+            (&self.timestamp, &self.line, &self.structured_metadata).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Entry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Raw(Timestamp, String, #[serde(default)] Labels);
+
+        let Raw(timestamp, line, structured_metadata) = Raw::deserialize(deserializer)?;
+        Ok(Entry {
+            timestamp,
+            line,
+            structured_metadata,
+        })
+    }
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Labels::default()
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.0.insert(name.into(), value.into())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.0.remove(name)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&str, &str) -> bool) {
+        self.0.retain(|name, value| keep(name, value))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Label names must match `[a-zA-Z_][a-zA-Z0-9_]*` and may not use the reserved `__` prefix
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for name in self.0.keys() {
+            let mut chars = name.chars();
+            let valid = chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid || name.starts_with("__") {
+                return Err(anyhow!("invalid label name {name:?}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Labels {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Labels(
+            iter.into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> Extend<(K, V)> for Labels {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        self.0.extend(
+            iter.into_iter()
+                .map(|(name, value)| (name.into(), value.into())),
+        )
+    }
+}
+
+/// Formats as a LogQL selector, `{app="foo", level="info"}`
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("{")?;
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{name}=\"")?;
+            for c in value.chars() {
+                match c {
+                    '\\' => f.write_str("\\\\")?,
+                    '"' => f.write_str("\\\"")?,
+                    '\n' => f.write_str("\\n")?,
+                    c => write!(f, "{c}")?,
+                }
+            }
+            f.write_str("\"")?;
+        }
+        f.write_str("}")
+    }
+}
+
+/// Parses the `{name="value", ...}` form protobuf pushes carry their labels in
+impl FromStr for Labels {
+    type Err = anyhow::Error;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let inner = selector
+            .trim()
+            .strip_prefix('{')
+            .and_then(|s| s.strip_suffix('}'))
+            .ok_or_else(|| anyhow!("label set must be wrapped in braces: {selector}"))?;
+
+        let mut labels = Labels::new();
+        let mut chars = inner.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+            if chars.peek().is_none() {
+                break;
+            }
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if name.is_empty() || chars.next() != Some('=') {
+                return Err(anyhow!("expected name=\"value\" in {selector}"));
+            }
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            if chars.next() != Some('"') {
+                return Err(anyhow!("expected quoted value for {name} in {selector}"));
+            }
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some(c) => value.push(c),
+                        None => return Err(anyhow!("unterminated escape in {selector}")),
+                    },
+                    Some(c) => value.push(c),
+                    None => return Err(anyhow!("unterminated value for {name} in {selector}")),
+                }
+            }
+            labels.insert(name, value);
+        }
+        Ok(labels)
+    }
+}
+
+impl Timestamp {
+    pub fn from_nanos(nanos: i64) -> Self {
+        Timestamp(nanos)
+    }
+
+    pub fn now() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i64)
+            .unwrap_or_default();
+        Timestamp(nanos)
+    }
+
+    pub fn nanos(&self) -> i64 {
+        self.0
+    }
+}
+
+// Loki's JSON carries timestamps as strings so they survive float parsing
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("unix nanoseconds as a string or integer")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Timestamp, E> {
+                v.parse().map(Timestamp).map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Timestamp, E> {
+                Ok(Timestamp(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Timestamp, E> {
+                i64::try_from(v).map(Timestamp).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUSH: &str = r#"{
+      "streams": [
+        {
+          "stream": {"hostname": "zoomer", "level": "info"},
+          "values": [
+            ["1724331600000000000", "collected data"],
+            ["1724331600000000001", "conditions nominal", {"trace_id": "abc"}]
+          ]
+        }
+      ]
+    }"#;
+
+    #[test]
+    fn json_round_trip() {
+        let push: PushRequest = serde_json::from_str(PUSH).unwrap();
+        assert_eq!(push.entries(), 2);
+        let stream = &push.streams[0];
+        assert_eq!(stream.labels.get("hostname"), Some("zoomer"));
+        assert_eq!(stream.values[0].timestamp.nanos(), 1724331600000000000);
+        assert!(stream.values[0].structured_metadata.is_empty());
+        assert_eq!(
+            stream.values[1].structured_metadata.get("trace_id"),
+            Some("abc")
+        );
+
+        let json = serde_json::to_string(&push).unwrap();
+        assert!(json.contains(r#"["1724331600000000000","collected data"]"#));
+        assert_eq!(serde_json::from_str::<PushRequest>(&json).unwrap(), push);
+    }
+
+    #[test]
+    fn label_set_round_trip() {
+        let labels: Labels = r#"{job="systemd", msg="say \"hi\"\n", path="C:\\"}"#
+            .parse()
+            .unwrap();
+        assert_eq!(labels.get("msg"), Some("say \"hi\"\n"));
+        assert_eq!(labels.get("path"), Some("C:\\"));
+        assert_eq!(labels.to_string().parse::<Labels>().unwrap(), labels);
+        assert!("job=\"x\"".parse::<Labels>().is_err());
+    }
+
+    #[test]
+    fn protobuf_round_trip() {
+        let push: PushRequest = serde_json::from_str(PUSH).unwrap();
+        let body = push.encode(Format::Protobuf).unwrap();
+        assert_eq!(PushRequest::decode(&body, Format::Protobuf).unwrap(), push);
+        assert_eq!(
+            PushRequest::sniff(&body).unwrap(),
+            (push.clone(), Format::Protobuf)
+        );
+        assert_eq!(
+            PushRequest::sniff(PUSH.as_bytes()).unwrap(),
+            (push, Format::Json)
+        );
+        assert!(PushRequest::sniff(b"not a push").is_err());
+    }
+
+    #[test]
+    fn gzip_bodies() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(PUSH.as_bytes()).unwrap();
+        let body = gz.finish().unwrap();
+
+        let (push, format) =
+            PushRequest::decode_http(&body, Some("application/json"), Some("gzip")).unwrap();
+        assert_eq!(format, Format::Json);
+        assert_eq!(PushRequest::sniff(&body).unwrap(), (push, Format::Json));
+        assert_eq!(sniff_encoding(&body), Some("gzip"));
+        assert!(decompress(&body, Some("br")).is_err());
+
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(&vec![0; MAX_DECODED_BYTES + 1]).unwrap();
+        let bomb = gz.finish().unwrap();
+        assert!(decompress(&bomb, Some("gzip")).is_err());
+    }
+
+    #[test]
+    fn validation() {
+        let push: PushRequest = serde_json::from_str(PUSH).unwrap();
+        assert!(push.validate().is_ok());
+        assert!(PushRequest::default().validate().is_err());
+
+        let mut bad = push.clone();
+        bad.streams[0].labels.insert("__name__", "x");
+        assert!(bad.validate().is_err());
+
+        let mut bad = push;
+        bad.streams[0].labels = Labels::new();
+        assert!(bad.validate().is_err());
+    }
 }
@@ -0,0 +1,187 @@
+//! Routing keys and topics rendered from stream labels
+
+use anyhow::anyhow;
+use std::fmt;
+use std::str::FromStr;
+
+/// What a field renders to when the stream has no such label
+pub const MISSING: &str = "_";
+
+/// Characters that separate or match words in an AMQP topic routing key
+pub const AMQP_RESERVED: &[char] = &['.', '*', '#'];
+
+/// Characters that separate or match levels in an MQTT topic
+pub const MQTT_RESERVED: &[char] = &['/', '+', '#'];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+/// A routing key or topic with `{field}`s filled in per stream, `logs.{tenant}.{hostname}.{level}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Whether every stream renders to the same key
+    pub fn is_static(&self) -> bool {
+        self.parts
+            .iter()
+            .all(|part| matches!(part, Part::Literal(_)))
+    }
+
+    /// Fill in every field with the value `lookup` finds for it
+    ///
+    /// `reserved` are the characters that separate or match words in the key, they are replaced
+    /// with `_` in values so a label can't add words or wildcards. Missing and empty values render
+    /// as [`MISSING`].
+    pub fn render<'a>(
+        &self,
+        lookup: impl Fn(&str) -> Option<&'a str>,
+        reserved: &[char],
+    ) -> String {
+        let mut key = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => key.push_str(literal),
+                Part::Field(name) => match lookup(name) {
+                    Some(value) if !value.is_empty() => {
+                        key.extend(value.chars().map(|c| match reserved.contains(&c) {
+                            true => '_',
+                            false => c,
+                        }))
+                    }
+                    _ => key.push_str(MISSING),
+                },
+            }
+        }
+        key
+    }
+
+    /// Read the fields back out of a rendered key, `None` when the key doesn't fit the template
+    ///
+    /// A field runs up to the next literal, fields rendered as [`MISSING`] are left out.
+    pub fn parse(&self, key: &str) -> Option<Vec<(String, String)>> {
+        let mut fields = vec![];
+        let mut rest = key;
+        let mut parts = self.parts.iter().peekable();
+        while let Some(part) = parts.next() {
+            match part {
+                Part::Literal(literal) => rest = rest.strip_prefix(literal.as_str())?,
+                Part::Field(name) => {
+                    let end = match parts.peek() {
+                        Some(Part::Literal(next)) => rest.find(next.as_str())?,
+                        _ => rest.len(),
+                    };
+                    let value = &rest[..end];
+                    if !value.is_empty() && value != MISSING {
+                        fields.push((name.clone(), value.to_string()));
+                    }
+                    rest = &rest[end..];
+                }
+            }
+        }
+        rest.is_empty().then_some(fields)
+    }
+
+    /// The filter subscribing to every key the template renders, any level holding a field
+    /// becomes `wildcard`
+    pub fn filter(&self, separator: char, wildcard: &str) -> String {
+        let placeholder = self.render(|_| Some("\0"), &[]);
+        placeholder
+            .split(separator)
+            .map(|level| match level.contains('\0') {
+                true => wildcard,
+                false => level,
+            })
+            .collect::<Vec<_>>()
+            .join(&separator.to_string())
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(open) = rest.find(['{', '}']) {
+            if rest[open..].starts_with('}') {
+                return Err(anyhow!("unmatched }} in {s}"));
+            }
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated {{ in {s}"))?;
+            let name = &rest[open + 1..open + close];
+            let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return Err(anyhow!("{{{name}}} in {s} is not a label name"));
+            }
+            parts.push(Part::Field(name.to_string()));
+            rest = &rest[open + close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(Template { parts })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => write!(f, "{literal}")?,
+                Part::Field(name) => write!(f, "{{{name}}}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_fields() {
+        let template: Template = "logs.{tenant}.{hostname}.{level}".parse().unwrap();
+        assert!(!template.is_static());
+        assert_eq!(template.to_string(), "logs.{tenant}.{hostname}.{level}");
+        let lookup = |name: &str| match name {
+            "tenant" => Some("team-a"),
+            "hostname" => Some("edge.01"),
+            _ => None,
+        };
+        assert_eq!(
+            template.render(lookup, &['.', '*', '#']),
+            "logs.team-a.edge_01._"
+        );
+
+        assert!("logs".parse::<Template>().unwrap().is_static());
+        assert!("logs.{tenant".parse::<Template>().is_err());
+        assert!("logs.{}".parse::<Template>().is_err());
+        assert!("logs}".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn parses_topics() {
+        let template: Template = "{tenant}/{hostname}/{app}/logs".parse().unwrap();
+        assert_eq!(template.filter('/', "+"), "+/+/+/logs");
+        assert_eq!(
+            template.parse("team-a/edge-01/_/logs"),
+            Some(vec![
+                ("tenant".to_string(), "team-a".to_string()),
+                ("hostname".to_string(), "edge-01".to_string()),
+            ])
+        );
+        assert_eq!(template.parse("team-a/edge-01/web/metrics"), None);
+    }
+}
@@ -1,36 +1,34 @@
 use clap::Parser;
 use clap_derive::Subcommand;
-use lapin::options::{BasicPublishOptions, ExchangeDeclareOptions};
-use lapin::types::FieldTable;
-use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind};
+use lapin::options::BasicPublishOptions;
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties};
 use log::info;
 use paho_mqtt as mqtt;
 use paho_mqtt::Message;
 
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-
-const LOGDATAJ: &str = r#"{
-  "streams": [
-    {
-      "stream": {
-        "hostname": "zoomer",
-        "hw_id": "f4:5c:89:c2:a6:07",
-        "sw_version": "v2.3.5",
-        "level": "info"
-      },
-      "values": [
-        [
-          "$TS$",
-          "2024-08-22T13:00:00Z [INFO] - collected data"
-        ],
-        [
-          "$TS$",
-          "2024-08-22T13:00:00Z [INFO] - conditions nominal"
-        ]
-      ]
+use oxxy::amqp::ExchangeArgs;
+use oxxy::shapes::{Entry, Labels, PushRequest, Stream, Timestamp};
+use oxxy::template::{Template, MQTT_RESERVED};
+use std::time::Duration;
+
+/// The json test push, every entry stamped with the current time
+fn logdataj() -> PushRequest {
+    let now = Timestamp::now();
+    PushRequest {
+        streams: vec![Stream {
+            labels: Labels::from_iter([
+                ("hostname", "zoomer"),
+                ("hw_id", "f4:5c:89:c2:a6:07"),
+                ("sw_version", "v2.3.5"),
+                ("level", "info"),
+            ]),
+            values: vec![
+                Entry::new(now, "2024-08-22T13:00:00Z [INFO] - collected data"),
+                Entry::new(now, "2024-08-22T13:00:00Z [INFO] - conditions nominal"),
+            ],
+        }],
     }
-  ]
-}"#;
+}
 
 // const LOGDATAM: &str = r#"\x80\x05\xf0\xc2\n\xfd\x04\nG{hostname="boomer", job="systemd-journal", unit="rtkit-daemon.service"}\x12@\n\x0c\x08\xe1\xfe\x9d\xb6\x06\x10\xe0\xd9\x83\xb4\x01\x120Supervising 4 threads of 4 processes of 1 users.\x12@\n\x0c\x08\xe1\xfe\x9d\xb6\x06\x10\xf0\x97\xb3\xb4\x01\x120Supervising 4 threads of 4 processe\x05Q\x001FB\x00\x10\xd8\xd4\x85\xf2\x02\xf2\x84\x00\x08\x80\xdb\xc4\xfaB\x00\x0c\x88\xe7\xbb\xf8\xf6\x84\x00\x0c\xd8\x82\xb5\xf9\xceB\x00\x04d\n1J\xa8\xc8\x9c\x83\x8c\x03\x12TSuccessfully made thread 464465 of p)\x94\xa8 463880 owned by \'1000\' RT at priority 10.\x129\xf2\x10\xa8\xf6\xae\x8c\x036n\x01\x005\rb%\xa1\x005\x11^4es of 1 users."#;
 
@@ -43,8 +41,9 @@ enum Commands {
         user: Option<String>,
         #[arg(short, long)]
         token: Option<String>,
+        /// Topic rendered from the test push's labels, such as `{tenant}/{hostname}/{app}/logs`
         #[arg(long)]
-        topic: String,
+        topic: Template,
         #[arg(short, long, default_value = "0")]
         qos: usize,
     },
@@ -60,6 +59,9 @@ enum Commands {
 
         #[clap(long, default_value = "logs")]
         routing_key: String,
+
+        #[command(flatten)]
+        topology: ExchangeArgs,
     },
 }
 
@@ -128,36 +130,20 @@ async fn main() -> Result<(), anyhow::Error> {
         info!("Publishing test message w/ {:?}", &args.cmd);
         match &args.cmd {
             Commands::AMQP {
-                rmq_uri: _,
                 queue,
-                routing_key: _tag,
                 exchange,
+                topology,
+                ..
             } => {
                 let channel = &statey.amqp.clone().unwrap();
 
-                let now = SystemTime::now();
-                channel
-                    .exchange_declare(
-                        exchange,                          // exchange name
-                        ExchangeKind::Direct,              // type of exchange
-                        ExchangeDeclareOptions::default(), // default options
-                        FieldTable::default(),             // no extra parameters
-                    )
-                    .await?;
+                topology.declare_exchange(channel, exchange).await?;
                 channel
                     .basic_publish(
                         exchange,
                         queue,
                         BasicPublishOptions::default(),
-                        LOGDATAJ
-                            .replace(
-                                "$TS$",
-                                now.duration_since(UNIX_EPOCH)?
-                                    .as_nanos()
-                                    .to_string()
-                                    .as_str(),
-                            )
-                            .as_ref(),
+                        &serde_json::to_vec(&logdataj())?,
                         BasicProperties::default(),
                     )
                     .await
@@ -173,9 +159,10 @@ async fn main() -> Result<(), anyhow::Error> {
                 qos: _,
             } => {
                 let cli = &statey.mqtt.clone().unwrap();
-                let now = SystemTime::now();
-                let ts = &now.duration_since(UNIX_EPOCH)?.as_nanos().to_string();
-                let payload = LOGDATAJ.replace("$TS$", ts);
+                let push = logdataj();
+                let labels = &push.streams[0].labels;
+                let topic = topic.render(|name| labels.get(name), MQTT_RESERVED);
+                let payload = serde_json::to_vec(&push)?;
                 let msg: Message = Message::new(topic, payload, 0);
                 cli.publish(msg).await?;
             }
@@ -0,0 +1,70 @@
+use anyhow::anyhow;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// Metadata sent ahead of every push carried over an iroh stream
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Header {
+    pub tenant: Option<String>,
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub content_encoding: Option<String>,
+}
+
+/// Frame a push as a big-endian u32 header length, the JSON header and then the raw body
+pub fn encode(header: &Header, body: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let head = serde_json::to_vec(header)?;
+    let mut framed = Vec::with_capacity(4 + head.len() + body.len());
+    framed.extend_from_slice(&(head.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&head);
+    framed.extend_from_slice(body);
+    Ok(framed)
+}
+
+pub fn decode(framed: &[u8]) -> Result<(Header, &[u8]), anyhow::Error> {
+    let (len, rest) = framed
+        .split_first_chunk::<4>()
+        .ok_or_else(|| anyhow!("frame too short"))?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(anyhow!("frame header truncated"));
+    }
+    let (head, body) = rest.split_at(len);
+    Ok((serde_json::from_slice(head)?, body))
+}
+
+/// Answer to a push, the status Loki or ioxxy settled it with as a big-endian u16
+pub fn encode_status(status: StatusCode) -> [u8; 2] {
+    status.as_u16().to_be_bytes()
+}
+
+pub fn decode_status(reply: &[u8]) -> Result<StatusCode, anyhow::Error> {
+    let status: [u8; 2] = reply
+        .try_into()
+        .map_err(|_| anyhow!("expected a 2 byte status, got {} bytes", reply.len()))?;
+    Ok(StatusCode::from_u16(u16::from_be_bytes(status))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_pushes_and_statuses() {
+        let header = Header {
+            tenant: Some("team-a".to_string()),
+            ..Default::default()
+        };
+        let framed = encode(&header, b"{}").unwrap();
+        let (decoded, body) = decode(&framed).unwrap();
+        assert_eq!(
+            (decoded.tenant.as_deref(), body),
+            (Some("team-a"), &b"{}"[..])
+        );
+        assert!(decode(&framed[..6]).is_err());
+
+        let status = encode_status(StatusCode::BAD_GATEWAY);
+        assert_eq!(decode_status(&status).unwrap(), StatusCode::BAD_GATEWAY);
+        assert!(decode_status(b"hi! you connected").is_err());
+    }
+}